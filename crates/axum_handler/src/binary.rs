@@ -0,0 +1,93 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use secure_js_sandbox::{ModuleCall, SandboxValue};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+/// Byte arrays are sent as `{"$bytes": "<base64>"}` in JSON requests and
+/// responses.
+const BYTES_KEY: &str = "$bytes";
+/// An object whose only key is `$bytes` is sent as
+/// `{"$bytes": {"$escaped": <value>}}`, so it isn't mistaken for bytes.
+const ESCAPED_KEY: &str = "$escaped";
+
+pub(crate) fn deserialize_parameters<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SandboxValue>, D::Error> {
+    Vec::<Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(from_json)
+        .collect::<Result<_, _>>()
+        .map_err(D::Error::custom)
+}
+
+pub(crate) fn deserialize_batch<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Vec<SandboxValue>>, D::Error> {
+    Vec::<Vec<Value>>::deserialize(deserializer)?
+        .into_iter()
+        .map(|parameters| parameters.into_iter().map(from_json).collect())
+        .collect::<Result<_, _>>()
+        .map_err(D::Error::custom)
+}
+
+#[derive(Deserialize)]
+struct SerializedModuleCall {
+    method: Box<str>,
+    #[serde(default, deserialize_with = "deserialize_parameters")]
+    parameters: Vec<SandboxValue>,
+}
+
+pub(crate) fn deserialize_module_calls<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<ModuleCall>, D::Error> {
+    Ok(Vec::<SerializedModuleCall>::deserialize(deserializer)?
+        .into_iter()
+        .map(|call| ModuleCall {
+            method: call.method,
+            parameters: call.parameters,
+        })
+        .collect())
+}
+
+/// The value of `$bytes`, if this object has no other keys.
+fn bytes_value(map: &serde_json::Map<String, Value>) -> Option<&Value> {
+    if map.len() == 1 {
+        map.get(BYTES_KEY)
+    } else {
+        None
+    }
+}
+
+fn from_json(value: Value) -> Result<SandboxValue, String> {
+    let Value::Object(map) = value else {
+        return Ok(SandboxValue::Json(value));
+    };
+    match bytes_value(&map) {
+        None => Ok(SandboxValue::Json(Value::Object(map))),
+        Some(Value::String(encoded)) => STANDARD
+            .decode(encoded)
+            .map(SandboxValue::Bytes)
+            .map_err(|err| format!("Invalid base64 in {BYTES_KEY}: {err}")),
+        Some(Value::Object(escaped)) if escaped.len() == 1 && escaped.contains_key(ESCAPED_KEY) => {
+            Ok(SandboxValue::Json(
+                serde_json::json!({ BYTES_KEY: escaped[ESCAPED_KEY] }),
+            ))
+        }
+        Some(_) => Err(format!(
+            "{BYTES_KEY} should be a base64 string or {{\"{ESCAPED_KEY}\": <value>}}"
+        )),
+    }
+}
+
+pub(crate) fn to_json(value: SandboxValue) -> Value {
+    match value {
+        SandboxValue::Json(Value::Object(map)) => match bytes_value(&map) {
+            Some(value) => serde_json::json!({ BYTES_KEY: { ESCAPED_KEY: value } }),
+            None => Value::Object(map),
+        },
+        SandboxValue::Json(value) => value,
+        SandboxValue::Bytes(bytes) => serde_json::json!({ BYTES_KEY: STANDARD.encode(bytes) }),
+    }
+}
@@ -2,7 +2,7 @@ use axum::{
     Json,
     routing::{MethodRouter, post},
 };
-use secure_js_sandbox::SandboxEngine;
+use secure_js_sandbox::{CancellationToken, SandboxEngine};
 use serde::de::DeserializeOwned;
 use std::sync::Arc;
 
@@ -11,20 +11,27 @@ use crate::{
     server_config::{EvaluateInput, set_request_body_limit},
 };
 
+/// `engine` should be built once from `config.get_engine_config()` and shared
+/// with the other handlers, so that they share its thread pool and epoch
+/// ticker.
 pub async fn create_evaluate_handler<
     TRequest: DeserializeOwned + Send + 'static,
     TConfig: CustomSandboxServerConfig<TRequest>,
     T: Clone + Send + Sync + 'static,
 >(
     config: TConfig,
+    engine: Arc<SandboxEngine>,
 ) -> anyhow::Result<MethodRouter<T>> {
     let limit = config.get_api_request_body_limit();
     let config = Arc::new(config);
-    let engine = Arc::new(SandboxEngine::new()?);
     let result: MethodRouter<T> = set_request_body_limit(
         post(
             async move |Json(request): Json<TRequest>| -> Json<EvaluateResponse> {
-                Json(evaluate(&config, request, &engine).await)
+                // Axum drops this future if the client disconnects, which
+                // cancels the evaluation and any outstanding outbound requests.
+                let cancel = CancellationToken::new();
+                let _cancel_on_drop = cancel.clone().drop_guard();
+                Json(evaluate(&config, request, &engine, &cancel).await)
             },
         ),
         limit,
@@ -39,6 +46,7 @@ pub async fn evaluate<
     config: &TConfig,
     request: TRequest,
     engine: &SandboxEngine,
+    cancel: &CancellationToken,
 ) -> EvaluateResponse {
     let EvaluateInput {
         code,
@@ -46,23 +54,8 @@ pub async fn evaluate<
         config,
     } = config.get_evaluate_input(request);
     let initial_cpu_fuel: u64 = config.cpu_fuel.into();
-    let result = engine.evaluate(&code, &parameters, config).await;
-    EvaluateResponse {
-        success: result.result.is_ok(),
-        stdout: result.stdout,
-        stderr: result.stderr,
-        fuel_consumed: initial_cpu_fuel.saturating_sub(result.fuel_remaining),
-        fuel_remaining: result.fuel_remaining,
-        max_requested_memory_bytes: result.max_requested_memory_bytes.unwrap_or(0),
-        max_requested_table_elements: result.max_requested_table_elements.unwrap_or(0),
-        outbound_requests: result
-            .outbound_requests
-            .into_iter()
-            .map(Into::into)
-            .collect(),
-        result: match result.result {
-            Ok(value) => value,
-            Err(err) => serde_json::json!({"error": err.to_string()}),
-        },
-    }
+    let result = engine
+        .evaluate_cancellable(&code, &parameters, config, cancel)
+        .await;
+    EvaluateResponse::from_result(result, initial_cpu_fuel)
 }
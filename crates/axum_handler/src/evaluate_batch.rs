@@ -0,0 +1,210 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    http::StatusCode,
+    routing::{MethodRouter, post},
+};
+use secure_js_sandbox::{
+    BatchBudget, CancellationToken, ModuleCall, SandboxBatchResult, SandboxEngine,
+    SandboxEvaluationResult, SandboxValue,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    CustomSandboxServerConfig, EvaluateResponse,
+    server_config::{EvaluateInput, set_request_body_limit},
+};
+
+#[derive(Deserialize)]
+pub struct EvaluateBatchRequest<TRequest> {
+    /// The code and config, as for `/evaluate`. Its `parameters` are ignored.
+    #[serde(flatten)]
+    pub request: TRequest,
+    /// The parameters for each call, in the same format as `parameters`.
+    #[serde(deserialize_with = "crate::binary::deserialize_batch")]
+    pub batch: Vec<Vec<SandboxValue>>,
+    /// Only used if the config allows requests to choose their budget. See
+    /// `CustomSandboxServerConfig::get_batch_budget`.
+    #[serde(default)]
+    pub budget: Option<BatchBudget>,
+}
+
+#[derive(Deserialize)]
+pub struct EvaluateModuleCallsRequest<TRequest> {
+    /// The code and config, as for `/evaluate`. Its `parameters` are ignored.
+    #[serde(flatten)]
+    pub request: TRequest,
+    /// The exports to call, in order. Each call's `parameters` are in the
+    /// same format as for `/evaluate`.
+    #[serde(deserialize_with = "crate::binary::deserialize_module_calls")]
+    pub calls: Vec<ModuleCall>,
+}
+
+#[derive(Serialize)]
+pub struct EvaluateBatchResponse {
+    /// The result of loading the code. If this failed, `items` is empty.
+    pub load: EvaluateResponse,
+    /// The response for each item in the batch, or each call. Their
+    /// `fuel_consumed` is the fuel used by that item alone.
+    pub items: Vec<EvaluateResponse>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BatchLimits {
+    /// How the CPU fuel and wall time limits apply to `/evaluate_batch`.
+    pub budget: BatchBudget,
+    /// The maximum number of items in a batch, or of calls to
+    /// `/evaluate_module_calls`.
+    pub max_items: usize,
+}
+
+impl Default for BatchLimits {
+    fn default() -> Self {
+        Self {
+            budget: BatchBudget::default(),
+            max_items: 1000,
+        }
+    }
+}
+
+type BatchError = (StatusCode, String);
+
+pub async fn create_evaluate_batch_handler<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+    T: Clone + Send + Sync + 'static,
+>(
+    config: TConfig,
+    engine: Arc<SandboxEngine>,
+) -> anyhow::Result<MethodRouter<T>> {
+    let limit = config.get_api_request_body_limit();
+    let config = Arc::new(config);
+    let result: MethodRouter<T> = set_request_body_limit(
+        post(
+            async move |Json(request): Json<EvaluateBatchRequest<TRequest>>| {
+                // Axum drops this future if the client disconnects, which
+                // cancels the rest of the batch.
+                let cancel = CancellationToken::new();
+                let _cancel_on_drop = cancel.clone().drop_guard();
+                evaluate_batch(&config, request, &engine, &cancel)
+                    .await
+                    .map(Json)
+            },
+        ),
+        limit,
+    );
+    Ok(result)
+}
+
+pub async fn evaluate_batch<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    config: &TConfig,
+    request: EvaluateBatchRequest<TRequest>,
+    engine: &SandboxEngine,
+    cancel: &CancellationToken,
+) -> Result<EvaluateBatchResponse, BatchError> {
+    let EvaluateBatchRequest {
+        request,
+        batch,
+        budget,
+    } = request;
+    check_batch_length(config, batch.len())?;
+    let budget = config.get_batch_budget(budget);
+    let EvaluateInput { code, config, .. } = config.get_evaluate_input(request);
+    let initial_cpu_fuel: u64 = config.cpu_fuel.into();
+    let result = engine
+        .evaluate_batch_cancellable(&code, &batch, config, budget, cancel)
+        .await;
+    Ok(batch_response(result, budget, initial_cpu_fuel))
+}
+
+/// Like `create_evaluate_batch_handler`, but evaluates the code as a module and
+/// calls the exports listed in the request's `calls`.
+pub async fn create_evaluate_module_calls_handler<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+    T: Clone + Send + Sync + 'static,
+>(
+    config: TConfig,
+    engine: Arc<SandboxEngine>,
+) -> anyhow::Result<MethodRouter<T>> {
+    let limit = config.get_api_request_body_limit();
+    let config = Arc::new(config);
+    let result: MethodRouter<T> = set_request_body_limit(
+        post(
+            async move |Json(request): Json<EvaluateModuleCallsRequest<TRequest>>| {
+                let cancel = CancellationToken::new();
+                let _cancel_on_drop = cancel.clone().drop_guard();
+                evaluate_module_calls(&config, request, &engine, &cancel)
+                    .await
+                    .map(Json)
+            },
+        ),
+        limit,
+    );
+    Ok(result)
+}
+
+pub async fn evaluate_module_calls<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    config: &TConfig,
+    request: EvaluateModuleCallsRequest<TRequest>,
+    engine: &SandboxEngine,
+    cancel: &CancellationToken,
+) -> Result<EvaluateBatchResponse, BatchError> {
+    let EvaluateModuleCallsRequest { request, calls } = request;
+    check_batch_length(config, calls.len())?;
+    let EvaluateInput { code, config, .. } = config.get_evaluate_input(request);
+    let initial_cpu_fuel: u64 = config.cpu_fuel.into();
+    let result = engine
+        .evaluate_module_calls_cancellable(&code, &calls, config, cancel)
+        .await;
+    Ok(batch_response(
+        result,
+        BatchBudget::Aggregate,
+        initial_cpu_fuel,
+    ))
+}
+
+fn check_batch_length<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    config: &TConfig,
+    length: usize,
+) -> Result<(), BatchError> {
+    let max_items = config.get_batch_limits().max_items;
+    if length > max_items {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("A batch can have at most {max_items} items"),
+        ));
+    }
+    Ok(())
+}
+
+fn batch_response(
+    result: SandboxBatchResult,
+    budget: BatchBudget,
+    initial_cpu_fuel: u64,
+) -> EvaluateBatchResponse {
+    let mut fuel_before = initial_cpu_fuel;
+    let mut to_response = |result: SandboxEvaluationResult| {
+        let initial_cpu_fuel = match budget {
+            BatchBudget::PerItem => initial_cpu_fuel,
+            BatchBudget::Aggregate => fuel_before,
+        };
+        fuel_before = result.fuel_remaining;
+        EvaluateResponse::from_result(result, initial_cpu_fuel)
+    };
+    EvaluateBatchResponse {
+        load: to_response(result.load),
+        items: result.items.into_iter().map(to_response).collect(),
+    }
+}
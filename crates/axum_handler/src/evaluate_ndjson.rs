@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    body::{Body, Bytes},
+    http::header,
+    routing::{MethodRouter, post},
+};
+use futures_util::{Stream, StreamExt, stream};
+use secure_js_sandbox::{CancellationToken, SandboxEngine, StreamConfig};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    CustomSandboxServerConfig, EvaluateResponse,
+    server_config::{EvaluateInput, set_request_body_limit},
+};
+
+/// One line of the response from `create_evaluate_ndjson_handler`.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum NdjsonLine {
+    /// A value yielded by the async iterator that the function returned. Byte
+    /// arrays are sent as `{"$bytes": "<base64>"}`.
+    Item { value: serde_json::Value },
+    /// Always the last line. Its `result` is the iterator's return value.
+    Result(EvaluateResponse),
+}
+
+/// Like `create_evaluate_handler`, but responds with newline-delimited JSON.
+/// If the function returns an async iterator, each value it yields is sent as
+/// an `item` line as soon as it is produced, followed by a `result` line with
+/// the `EvaluateResponse`. The sandbox is paused while the client isn't
+/// reading and the buffer is full.
+pub async fn create_evaluate_ndjson_handler<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+    T: Clone + Send + Sync + 'static,
+>(
+    config: TConfig,
+    engine: Arc<SandboxEngine>,
+) -> anyhow::Result<MethodRouter<T>> {
+    let limit = config.get_api_request_body_limit();
+    let config = Arc::new(config);
+    let result: MethodRouter<T> = set_request_body_limit(
+        post(async move |Json(request): Json<TRequest>| {
+            (
+                [(header::CONTENT_TYPE, "application/x-ndjson")],
+                Body::from_stream(evaluate_ndjson(&config, request, engine.clone())),
+            )
+        }),
+        limit,
+    );
+    Ok(result)
+}
+
+pub fn evaluate_ndjson<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    config: &TConfig,
+    request: TRequest,
+    engine: Arc<SandboxEngine>,
+) -> impl Stream<Item = Result<Bytes, axum::Error>> + Send + use<TRequest, TConfig> {
+    let (stream_config, receiver) = StreamConfig::channel(config.get_stream_limits());
+    let EvaluateInput {
+        code,
+        parameters,
+        mut config,
+    } = config.get_evaluate_input(request);
+    let initial_cpu_fuel: u64 = config.cpu_fuel.into();
+    config.stream = Some(stream_config);
+
+    // The evaluation runs in its own task so that items can be sent while it
+    // runs. It is cancelled if the client disconnects.
+    let cancel = CancellationToken::new();
+    let task_cancel = cancel.clone();
+    let evaluation = tokio::spawn(async move {
+        engine
+            .evaluate_cancellable(&code, &parameters, config, &task_cancel)
+            .await
+    });
+
+    // The sink is dropped along with the sandbox, which ends this stream.
+    let items = stream::unfold(
+        (receiver, cancel.drop_guard()),
+        |(mut receiver, cancel_on_drop)| async move {
+            let item = receiver.recv().await?;
+            let line = NdjsonLine::Item {
+                value: crate::binary::to_json(item),
+            };
+            Some((line, (receiver, cancel_on_drop)))
+        },
+    );
+    let result = stream::once(async move {
+        match evaluation.await {
+            Ok(result) => Ok(NdjsonLine::Result(EvaluateResponse::from_result(
+                result,
+                initial_cpu_fuel,
+            ))),
+            Err(err) => Err(axum::Error::new(err)),
+        }
+    });
+    items
+        .map(Ok)
+        .chain(result)
+        .map(|line| line.and_then(|line| to_ndjson(&line)))
+}
+
+fn to_ndjson(line: &NdjsonLine) -> Result<Bytes, axum::Error> {
+    let mut bytes = serde_json::to_vec(line).map_err(axum::Error::new)?;
+    bytes.push(b'\n');
+    Ok(Bytes::from(bytes))
+}
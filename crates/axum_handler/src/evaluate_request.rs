@@ -1,4 +1,7 @@
-use secure_js_sandbox::{CpuFuel, HttpMode, RequestLimit};
+use secure_js_sandbox::{
+    CpuFuel, Determinism, HttpMode, KvLimits, RequestLimit, ResultLimits, SandboxValue,
+    ValueEncoding, WallTimeLimit,
+};
 use serde::Deserialize;
 
 use crate::SandboxServerMemoryLimits;
@@ -6,8 +9,21 @@ use crate::SandboxServerMemoryLimits;
 #[derive(Deserialize)]
 pub struct EvaluateRequest {
     pub code: String,
-    pub parameters: Vec<serde_json::Value>,
+    /// JSON values, or `{"$bytes": "<base64>"}` for a `Uint8Array`.
+    #[serde(default, deserialize_with = "crate::binary::deserialize_parameters")]
+    pub parameters: Vec<SandboxValue>,
     pub filename: Option<String>,
+    /// Seed for the random number generators. Unless `SANDBOX_DETERMINISTIC`
+    /// is set, the clocks still tell the real time.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Overrides `SANDBOX_VALUE_ENCODING` for this request.
+    #[serde(default)]
+    pub value_encoding: Option<ValueEncoding>,
+    /// Return a CPU profile of the evaluation. Ignored unless
+    /// `SANDBOX_ALLOW_PROFILING` is set.
+    #[serde(default)]
+    pub profile: bool,
 }
 
 #[derive(Default, Deserialize)]
@@ -24,12 +40,29 @@ pub struct SandboxServerRequestConfig {
     pub sandbox_auto_strip_types: bool,
     #[serde(default)]
     pub module_method: Option<Box<str>>,
+    #[serde(default)]
+    pub wall_time_limit: WallTimeLimit,
+    #[serde(default)]
+    pub determinism: Option<Determinism>,
+    #[serde(default)]
+    pub virtual_time: bool,
+    #[serde(default)]
+    pub kv_namespace: Option<String>,
+    #[serde(default)]
+    pub kv_limits: KvLimits,
+    #[serde(default)]
+    pub value_encoding: ValueEncoding,
+    #[serde(default)]
+    pub result_limits: ResultLimits,
+    #[serde(default)]
+    pub profile: bool,
 }
 
 #[derive(Deserialize)]
 pub struct EvaluateRequestWithConfig {
     pub code: String,
-    pub parameters: Vec<serde_json::Value>,
+    #[serde(default, deserialize_with = "crate::binary::deserialize_parameters")]
+    pub parameters: Vec<SandboxValue>,
     pub filename: Option<String>,
     #[serde(default)]
     pub config: SandboxServerRequestConfig,
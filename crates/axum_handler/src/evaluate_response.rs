@@ -1,4 +1,7 @@
-use secure_js_sandbox::{OutboundRequest, RequestValidationOutcome};
+use secure_js_sandbox::{
+    EvaluateError, HostCall, JavaScriptError, LogRecord, OutboundRequest, Phase, PhaseUsage,
+    RequestValidationOutcome, SandboxEvaluationResult,
+};
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -6,12 +9,74 @@ pub struct EvaluateResponse {
     pub success: bool,
     pub stdout: String,
     pub stderr: String,
+    pub logs: Vec<LogRecord>,
+    /// Whether output was dropped because it exceeded the stdout or stderr
+    /// byte limits.
+    pub truncated: bool,
     pub fuel_consumed: u64,
     pub fuel_remaining: u64,
     pub max_requested_memory_bytes: usize,
     pub max_requested_table_elements: usize,
     pub outbound_requests: Vec<SerializableOutboundRequest>,
+    pub host_calls: Vec<SerializableHostCall>,
+    /// The fuel and time used by each phase of the evaluation.
+    pub phases: Vec<SerializablePhaseUsage>,
+    /// Byte arrays are returned as `{"$bytes": "<base64>"}`.
     pub result: serde_json::Value,
+    /// Details of the error, if the JavaScript code threw one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<JavaScriptError>,
+    /// The seed used for the random number generators, if the evaluation
+    /// was deterministic. Pass this back in to replay the evaluation.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// How far timers were fast-forwarded, if virtual time was enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub virtual_time_elapsed_ms: Option<f64>,
+    /// A CPU profile in the Firefox Profiler's format, if one was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<serde_json::Value>,
+}
+impl EvaluateResponse {
+    pub(crate) fn from_result(result: SandboxEvaluationResult, initial_cpu_fuel: u64) -> Self {
+        let (success, value, error) = match result.result {
+            Ok(value) => (true, crate::binary::to_json(value), None),
+            Err(err) => {
+                let value = serde_json::json!({"error": err.to_string()});
+                match err {
+                    EvaluateError::JavaScriptError(error) => (false, value, Some(error)),
+                    _ => (false, value, None),
+                }
+            }
+        };
+        EvaluateResponse {
+            success,
+            stdout: result.stdout,
+            stderr: result.stderr,
+            logs: result.logs,
+            truncated: result.logs_truncated,
+            fuel_consumed: initial_cpu_fuel.saturating_sub(result.fuel_remaining),
+            fuel_remaining: result.fuel_remaining,
+            max_requested_memory_bytes: result.max_requested_memory_bytes.unwrap_or(0),
+            max_requested_table_elements: result.max_requested_table_elements.unwrap_or(0),
+            outbound_requests: result
+                .outbound_requests
+                .into_iter()
+                .map(Into::into)
+                .collect(),
+            host_calls: result.host_calls.into_iter().map(Into::into).collect(),
+            phases: result.phases.into_iter().map(Into::into).collect(),
+            result: value,
+            error,
+            seed: result.seed,
+            virtual_time_elapsed_ms: result
+                .virtual_time_elapsed
+                .map(|elapsed| elapsed.as_secs_f64() * 1000.0),
+            profile: result
+                .profile
+                .and_then(|profile| serde_json::from_slice(&profile).ok()),
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -29,3 +94,43 @@ impl From<OutboundRequest> for SerializableOutboundRequest {
         }
     }
 }
+
+#[derive(Serialize)]
+pub struct SerializableHostCall {
+    pub name: String,
+    pub success: bool,
+    pub duration_ms: f64,
+}
+impl From<HostCall> for SerializableHostCall {
+    fn from(call: HostCall) -> Self {
+        SerializableHostCall {
+            name: call.name,
+            success: call.success,
+            duration_ms: call.duration.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SerializablePhaseUsage {
+    pub phase: Phase,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    pub fuel_consumed: u64,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetch_ms: Option<f64>,
+}
+impl From<PhaseUsage> for SerializablePhaseUsage {
+    fn from(usage: PhaseUsage) -> Self {
+        SerializablePhaseUsage {
+            phase: usage.phase,
+            module: usage.module,
+            fuel_consumed: usage.fuel_consumed,
+            duration_ms: usage.duration.as_secs_f64() * 1000.0,
+            fetch_ms: usage
+                .fetch_duration
+                .map(|duration| duration.as_secs_f64() * 1000.0),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    response::sse::{Event, Sse},
+    routing::{MethodRouter, post},
+};
+use futures_util::{Stream, StreamExt, stream};
+use secure_js_sandbox::{CancellationToken, OutputChunk, OutputKind, SandboxEngine};
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+use crate::{
+    CustomSandboxServerConfig, EvaluateResponse,
+    server_config::{EvaluateInput, set_request_body_limit},
+};
+
+/// How many chunks of output can be waiting for a slow client. Any more are
+/// dropped from the events, but are still in the `result` event.
+const OUTPUT_BUFFER_CHUNKS: usize = 256;
+
+/// Like `create_evaluate_handler`, but responds with Server-Sent Events.
+/// Each chunk of output is sent as a `stdout` or `stderr` event as soon as it
+/// is written, followed by a `result` event with the `EvaluateResponse`.
+pub async fn create_evaluate_stream_handler<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+    T: Clone + Send + Sync + 'static,
+>(
+    config: TConfig,
+    engine: Arc<SandboxEngine>,
+) -> anyhow::Result<MethodRouter<T>> {
+    let limit = config.get_api_request_body_limit();
+    let config = Arc::new(config);
+    let result: MethodRouter<T> = set_request_body_limit(
+        post(async move |Json(request): Json<TRequest>| {
+            Sse::new(evaluate_stream(&config, request, engine.clone()))
+        }),
+        limit,
+    );
+    Ok(result)
+}
+
+pub fn evaluate_stream<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    config: &TConfig,
+    request: TRequest,
+    engine: Arc<SandboxEngine>,
+) -> impl Stream<Item = Result<Event, axum::Error>> + Send + use<TRequest, TConfig> {
+    let EvaluateInput {
+        code,
+        parameters,
+        mut config,
+    } = config.get_evaluate_input(request);
+    let initial_cpu_fuel: u64 = config.cpu_fuel.into();
+    let (sender, receiver) = mpsc::channel(OUTPUT_BUFFER_CHUNKS);
+    config.output_sink = Some(sender);
+
+    // The evaluation runs in its own task so that output can be streamed
+    // while it runs. It is cancelled if the client disconnects.
+    let cancel = CancellationToken::new();
+    let task_cancel = cancel.clone();
+    let evaluation = tokio::spawn(async move {
+        engine
+            .evaluate_cancellable(&code, &parameters, config, &task_cancel)
+            .await
+    });
+
+    // The sender is dropped along with the sandbox, which ends this stream.
+    let output = stream::unfold(
+        (receiver, cancel.drop_guard()),
+        |(mut receiver, cancel_on_drop)| async move {
+            let chunk = receiver.recv().await?;
+            Some((output_event(chunk), (receiver, cancel_on_drop)))
+        },
+    );
+    let result = stream::once(async move {
+        let response = match evaluation.await {
+            Ok(result) => EvaluateResponse::from_result(result, initial_cpu_fuel),
+            Err(err) => return Err(axum::Error::new(err)),
+        };
+        Event::default().event("result").json_data(response)
+    });
+    output.map(Ok).chain(result)
+}
+
+fn output_event(chunk: OutputChunk) -> Event {
+    Event::default()
+        .event(match chunk.kind {
+            OutputKind::Stdout => "stdout",
+            OutputKind::Stderr => "stderr",
+        })
+        .data(chunk.text)
+}
@@ -1,26 +1,41 @@
 #![deny(warnings, clippy::all, clippy::pedantic, clippy::unwrap_used)]
 #![allow(clippy::missing_errors_doc, clippy::unused_async_trait_impl)]
 
+mod binary;
 mod env;
 mod evaluate;
+mod evaluate_batch;
+mod evaluate_ndjson;
 mod evaluate_request;
 mod evaluate_response;
+mod evaluate_stream;
 mod server_config;
+mod sessions;
 mod ts_utils;
 
 pub use crate::env::get_env;
 pub use crate::evaluate::{create_evaluate_handler, evaluate};
+pub use crate::evaluate_batch::{
+    BatchLimits, EvaluateBatchRequest, EvaluateBatchResponse, EvaluateModuleCallsRequest,
+    create_evaluate_batch_handler, create_evaluate_module_calls_handler, evaluate_batch,
+    evaluate_module_calls,
+};
+pub use crate::evaluate_ndjson::{NdjsonLine, create_evaluate_ndjson_handler, evaluate_ndjson};
 pub use crate::evaluate_request::{EvaluateRequest, EvaluateRequestWithConfig};
-pub use crate::evaluate_response::{EvaluateResponse, SerializableOutboundRequest};
+pub use crate::evaluate_response::{
+    EvaluateResponse, SerializableHostCall, SerializableOutboundRequest, SerializablePhaseUsage,
+};
+pub use crate::evaluate_stream::{create_evaluate_stream_handler, evaluate_stream};
 pub use crate::server_config::{
     AllowRequestToConfigureSandbox, CustomSandboxServerConfig, SandboxServerConfig,
     SandboxServerMemoryLimits,
 };
+pub use crate::sessions::{CreateSessionResponse, SessionLimits, create_sessions_router};
 pub use crate::ts_utils::{
     StripTypesRequest, StripTypesResponse, StripTypesResponseSuccess, TsResponseFailure,
     TsUtilsHandler, ValidateModuleRequest, create_strip_types_handler,
     create_validate_module_handler, strip_types, validate_module,
 };
 pub use secure_js_sandbox::{
-    CustomHttpMode, HttpMode, MemoryLimits, MemoryOutputPipe, TsUtilsSandboxConfig,
+    CustomHttpMode, HttpMode, MemoryLimits, MemoryOutputPipe, SandboxEngine, TsUtilsSandboxConfig,
 };
@@ -7,20 +7,25 @@ use axum::routing::MethodRouter;
 use serde::{Deserialize, de::DeserializeOwned};
 
 use secure_js_sandbox::{
-    ApiRequestBodyLimit, CpuFuel, CustomHttpMode, CustomImportMap, EvaluateMode, HttpMode,
-    ImportMap, MemoryLimitBytes, MemoryLimits, MemorySizeBytes, RequestLimit, ResourceLimit,
-    SandboxConfig, StaticImportSource, TableLimit,
+    ApiRequestBodyLimit, BatchBudget, ClockMode, CpuFuel, CpuTime, CustomHttpMode, CustomImportMap,
+    Determinism, EvaluateMode, FileKvStore, FuelCalibration, HttpMode, ImportMap,
+    InstancePoolLimits, KvConfig, KvLimits, KvStore, MemoryKvStore, MemoryLimitBytes, MemoryLimits,
+    MemorySizeBytes, RequestLimit, ResourceLimit, ResultLimits, SandboxConfig, SandboxEngineConfig,
+    SandboxValue, Secret, Secrets, StaticImportSource, StreamLimits, TableLimit, ValueEncoding,
+    WallTimeLimit,
 };
 
 use crate::env::get_env;
+use crate::evaluate_batch::BatchLimits;
 use crate::evaluate_request::{EvaluateRequest, EvaluateRequestWithConfig};
+use crate::sessions::SessionLimits;
 
 pub struct EvaluateInput<
     THttpMode: CustomHttpMode = HttpMode,
     TImportMap: CustomImportMap = ImportMap,
 > {
     pub code: String,
-    pub parameters: Vec<serde_json::Value>,
+    pub parameters: Vec<SandboxValue>,
     pub config: SandboxConfig<THttpMode, TImportMap>,
 }
 
@@ -33,6 +38,24 @@ pub trait CustomSandboxServerConfig<
 {
     fn get_api_request_body_limit(&self) -> ApiRequestBodyLimit;
     fn get_evaluate_input(&self, request: TRequestType) -> EvaluateInput<THttpMode, TImportMap>;
+    fn get_engine_config(&self) -> SandboxEngineConfig {
+        SandboxEngineConfig::default()
+    }
+    fn get_session_limits(&self) -> SessionLimits {
+        SessionLimits::default()
+    }
+    fn get_stream_limits(&self) -> StreamLimits {
+        StreamLimits::default()
+    }
+    fn get_batch_limits(&self) -> BatchLimits {
+        BatchLimits::default()
+    }
+    /// The budget for a call to `/evaluate_batch`. `_requested` is the
+    /// request's own `budget`, which is ignored unless the request is
+    /// trusted to configure the sandbox.
+    fn get_batch_budget(&self, _requested: Option<BatchBudget>) -> BatchBudget {
+        self.get_batch_limits().budget
+    }
 }
 
 impl<
@@ -48,6 +71,21 @@ impl<
     fn get_evaluate_input(&self, request: TRequestType) -> EvaluateInput<THttpMode, TImportMap> {
         self.as_ref().get_evaluate_input(request)
     }
+    fn get_engine_config(&self) -> SandboxEngineConfig {
+        self.as_ref().get_engine_config()
+    }
+    fn get_session_limits(&self) -> SessionLimits {
+        self.as_ref().get_session_limits()
+    }
+    fn get_stream_limits(&self) -> StreamLimits {
+        self.as_ref().get_stream_limits()
+    }
+    fn get_batch_limits(&self) -> BatchLimits {
+        self.as_ref().get_batch_limits()
+    }
+    fn get_batch_budget(&self, requested: Option<BatchBudget>) -> BatchBudget {
+        self.as_ref().get_batch_budget(requested)
+    }
 }
 
 fn default_trap_on_grow_failure() -> bool {
@@ -112,6 +150,149 @@ impl SandboxServerMemoryLimits {
     }
 }
 
+fn instance_pool_from_env() -> anyhow::Result<Option<InstancePoolLimits>> {
+    if !get_env("SANDBOX_POOLING_ALLOCATOR")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    let mut result = InstancePoolLimits::default();
+    set_from_env!(result, max_sandboxes, "SANDBOX_POOL", "MAX_SANDBOXES");
+    set_from_env!(
+        result,
+        core_instances_per_sandbox,
+        "SANDBOX_POOL",
+        "CORE_INSTANCES"
+    );
+    set_from_env!(result, memories_per_sandbox, "SANDBOX_POOL", "MEMORIES");
+    set_from_env!(result, tables_per_sandbox, "SANDBOX_POOL", "TABLES");
+    set_from_env!(
+        result,
+        max_memory_size_bytes,
+        "SANDBOX_POOL",
+        "MAX_MEMORY_BYTES"
+    );
+    set_from_env!(result, table_elements, "SANDBOX_POOL", "MAX_TABLE_ELEMENTS");
+    Ok(Some(result))
+}
+
+fn engine_config_from_env() -> anyhow::Result<SandboxEngineConfig> {
+    let mut result = SandboxEngineConfig {
+        instance_pool: instance_pool_from_env()?,
+        ..SandboxEngineConfig::default()
+    };
+    if let Some(interval) = get_env::<CpuFuel>("SANDBOX_FUEL_YIELD_INTERVAL")? {
+        result.fuel_yield_interval = Some(interval.into());
+    }
+    if let Some(threads) = get_env("SANDBOX_COMPUTE_THREADS")? {
+        result.compute_threads = Some(threads);
+    }
+    Ok(result)
+}
+
+fn determinism_from_env() -> anyhow::Result<Option<Determinism>> {
+    if !get_env("SANDBOX_DETERMINISTIC")?.unwrap_or(false) {
+        return Ok(None);
+    }
+    Ok(Some(Determinism {
+        seed: None,
+        start_time_ms: get_env("SANDBOX_DETERMINISTIC_START_TIME_MS")?.unwrap_or(0),
+        clock: get_env("SANDBOX_DETERMINISTIC_CLOCK")?.unwrap_or_default(),
+    }))
+}
+
+fn kv_store_from_env() -> anyhow::Result<Option<Arc<dyn KvStore>>> {
+    let Some(store) = get_env::<String>("SANDBOX_KV_STORE")? else {
+        return Ok(None);
+    };
+    if store == "MEMORY" {
+        return Ok(Some(Arc::new(MemoryKvStore::new())));
+    }
+    match store.strip_prefix("FILE:") {
+        Some(dir) => Ok(Some(Arc::new(FileKvStore::new(dir)?))),
+        None => Err(anyhow::anyhow!(
+            "SANDBOX_KV_STORE should be MEMORY or FILE:<directory>, got {store}"
+        )),
+    }
+}
+
+fn default_kv_namespace() -> String {
+    "default".to_string()
+}
+
+fn kv_limits_from_env() -> anyhow::Result<KvLimits> {
+    let mut result = KvLimits::default();
+    set_from_env!(result, max_operations, "SANDBOX_KV", "MAX_OPERATIONS");
+    set_from_env!(result, max_bytes, "SANDBOX_KV", "MAX_BYTES");
+    set_from_env!(result, max_value_bytes, "SANDBOX_KV", "MAX_VALUE_BYTES");
+    set_from_env!(result, max_keys, "SANDBOX_KV", "MAX_KEYS");
+    set_from_env!(result, max_stored_bytes, "SANDBOX_KV", "MAX_STORED_BYTES");
+    Ok(result)
+}
+
+fn result_limits_from_env() -> anyhow::Result<ResultLimits> {
+    let mut result = ResultLimits::default();
+    set_from_env!(result, max_bytes, "SANDBOX_RESULT", "MAX_BYTES");
+    set_from_env!(result, max_depth, "SANDBOX_RESULT", "MAX_DEPTH");
+    Ok(result)
+}
+
+fn stream_limits_from_env() -> anyhow::Result<StreamLimits> {
+    let mut result = StreamLimits::default();
+    set_from_env!(result, max_items, "SANDBOX_STREAM", "MAX_ITEMS");
+    set_from_env!(result, buffer_items, "SANDBOX_STREAM", "BUFFER_ITEMS");
+    Ok(result)
+}
+
+fn kv_config(
+    store: Option<&Arc<dyn KvStore>>,
+    namespace: Option<String>,
+    limits: KvLimits,
+) -> Option<KvConfig> {
+    Some(KvConfig {
+        store: store?.clone(),
+        namespace: namespace?,
+        limits,
+    })
+}
+
+fn secrets_from_env() -> anyhow::Result<Secrets> {
+    let Some(secrets_path) = get_env::<PathBuf>("SANDBOX_SECRETS_FILE")? else {
+        return Ok(Secrets::default());
+    };
+    let secrets_content = std::fs::read_to_string(&secrets_path).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to read secrets file {}: {}",
+            secrets_path.display(),
+            e
+        )
+    })?;
+    let secrets: HashMap<String, Secret> = serde_json::from_str(&secrets_content).map_err(|e| {
+        anyhow::anyhow!(
+            "Failed to parse secrets file {}: {}",
+            secrets_path.display(),
+            e
+        )
+    })?;
+    Ok(Secrets::new(secrets))
+}
+
+fn batch_limits_from_env() -> anyhow::Result<BatchLimits> {
+    let mut result = BatchLimits::default();
+    set_from_env!(result, budget, "SANDBOX_BATCH", "BUDGET");
+    if let Some(max_items) = get_env::<ResourceLimit>("SANDBOX_BATCH_MAX_ITEMS")? {
+        result.max_items = max_items.into();
+    }
+    Ok(result)
+}
+
+fn session_limits_from_env() -> anyhow::Result<SessionLimits> {
+    let mut result = SessionLimits::default();
+    set_from_env!(result, idle_timeout, "SANDBOX", "SESSION_IDLE_TIMEOUT");
+    if let Some(max_sessions) = get_env::<ResourceLimit>("SANDBOX_MAX_SESSIONS")? {
+        result.max_sessions = max_sessions.into();
+    }
+    Ok(result)
+}
+
 pub struct SandboxServerConfig<
     THttpMode: CustomHttpMode = HttpMode,
     TImportMap: CustomImportMap + Clone = ImportMap,
@@ -124,6 +305,33 @@ pub struct SandboxServerConfig<
     pub import_map: TImportMap,
     pub sandbox_auto_strip_types: bool,
     pub module_method: Option<Box<str>>,
+    pub engine_config: SandboxEngineConfig,
+    pub session_limits: SessionLimits,
+    pub wall_time_limit: WallTimeLimit,
+    /// Use fixed clocks and seeded random number generators for every
+    /// evaluation. Requests can still pass their own `seed`, which only seeds
+    /// the random number generators if this is `None`.
+    pub determinism: Option<Determinism>,
+    /// Fast-forward timers instead of waiting for them in real time.
+    pub virtual_time: bool,
+    /// Every evaluation can use this store, in `kv_namespace`.
+    pub kv_store: Option<Arc<dyn KvStore>>,
+    /// Requests can't choose their own namespace, as they could then read
+    /// each other's keys. To give each caller its own namespace, implement
+    /// `CustomSandboxServerConfig` and derive it from the caller's identity.
+    pub kv_namespace: String,
+    pub kv_limits: KvLimits,
+    /// Exposed to every evaluation as the `secrets` global.
+    pub secrets: Secrets,
+    /// Requests can override this with their own `value_encoding`.
+    pub value_encoding: ValueEncoding,
+    pub result_limits: ResultLimits,
+    /// Limits the items sent by the NDJSON handler.
+    pub stream_limits: StreamLimits,
+    /// Requests to `/evaluate_batch` can't choose their own budget.
+    pub batch_limits: BatchLimits,
+    /// Return a CPU profile to requests that pass `profile: true`.
+    pub allow_profiling: bool,
 }
 
 impl Default for SandboxServerConfig {
@@ -137,6 +345,20 @@ impl Default for SandboxServerConfig {
             import_map: ImportMap::default(),
             sandbox_auto_strip_types: false,
             module_method: None,
+            engine_config: SandboxEngineConfig::default(),
+            session_limits: SessionLimits::default(),
+            wall_time_limit: WallTimeLimit::default(),
+            determinism: None,
+            virtual_time: false,
+            kv_store: None,
+            kv_namespace: default_kv_namespace(),
+            kv_limits: KvLimits::default(),
+            secrets: Secrets::default(),
+            value_encoding: ValueEncoding::default(),
+            result_limits: ResultLimits::default(),
+            stream_limits: StreamLimits::default(),
+            batch_limits: BatchLimits::default(),
+            allow_profiling: false,
         }
     }
 }
@@ -152,8 +374,34 @@ impl SandboxServerConfig {
             import_map: import_map_from_env()?,
             sandbox_auto_strip_types: get_env("SANDBOX_AUTO_STRIP_TYPES")?.unwrap_or(false),
             module_method: get_env::<String>("SANDBOX_MODULE_METHOD")?.map(String::into_boxed_str),
+            engine_config: engine_config_from_env()?,
+            session_limits: session_limits_from_env()?,
+            wall_time_limit: get_env("SANDBOX_WALL_TIME_LIMIT")?.unwrap_or_default(),
+            determinism: determinism_from_env()?,
+            virtual_time: get_env("SANDBOX_VIRTUAL_TIME")?.unwrap_or(false),
+            kv_store: kv_store_from_env()?,
+            kv_namespace: get_env("SANDBOX_KV_NAMESPACE")?.unwrap_or_else(default_kv_namespace),
+            kv_limits: kv_limits_from_env()?,
+            secrets: secrets_from_env()?,
+            value_encoding: get_env("SANDBOX_VALUE_ENCODING")?.unwrap_or_default(),
+            result_limits: result_limits_from_env()?,
+            stream_limits: stream_limits_from_env()?,
+            batch_limits: batch_limits_from_env()?,
+            allow_profiling: get_env("SANDBOX_ALLOW_PROFILING")?.unwrap_or(false),
         })
     }
+
+    /// If `SANDBOX_CPU_TIME` is set, measure how fast this machine consumes
+    /// fuel, and set `cpu_fuel` to the fuel that takes that long. This
+    /// overrides `SANDBOX_CPU_FUEL`.
+    pub async fn calibrate_cpu_fuel_from_env(&mut self) -> anyhow::Result<Option<FuelCalibration>> {
+        let Some(cpu_time) = get_env::<CpuTime>("SANDBOX_CPU_TIME")? else {
+            return Ok(None);
+        };
+        let calibration = FuelCalibration::measure().await?;
+        self.cpu_fuel = calibration.fuel_for(cpu_time);
+        Ok(Some(calibration))
+    }
 }
 
 impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap + Clone>
@@ -164,6 +412,15 @@ impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap + Clone>
         self.api_request_body_limit
     }
     fn get_evaluate_input(&self, request: EvaluateRequest) -> EvaluateInput<THttpMode, TImportMap> {
+        let mut determinism = self.determinism.clone();
+        if let Some(seed) = request.seed {
+            determinism
+                .get_or_insert_with(|| Determinism {
+                    clock: ClockMode::Real,
+                    ..Determinism::default()
+                })
+                .seed = Some(seed);
+        }
         EvaluateInput {
             code: request.code,
             parameters: request.parameters,
@@ -179,22 +436,71 @@ impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap + Clone>
                 },
                 strip_typescript_types: self.sandbox_auto_strip_types,
                 filename: request.filename,
+                value_encoding: request.value_encoding.unwrap_or(self.value_encoding),
+                result_limits: self.result_limits,
+                wall_time_limit: self.wall_time_limit,
+                output_sink: None,
+                stream: None,
+                determinism,
+                virtual_time: self.virtual_time,
+                kv: kv_config(
+                    self.kv_store.as_ref(),
+                    Some(self.kv_namespace.clone()),
+                    self.kv_limits,
+                ),
+                secrets: self.secrets.clone(),
+                profile: self.allow_profiling && request.profile,
             },
         }
     }
+    fn get_engine_config(&self) -> SandboxEngineConfig {
+        self.engine_config.clone()
+    }
+    fn get_session_limits(&self) -> SessionLimits {
+        self.session_limits.clone()
+    }
+    fn get_stream_limits(&self) -> StreamLimits {
+        self.stream_limits
+    }
+    fn get_batch_limits(&self) -> BatchLimits {
+        self.batch_limits
+    }
 }
 
 #[derive(Default)]
 pub struct AllowRequestToConfigureSandbox<TImportMap: CustomImportMap + Clone = ImportMap> {
     pub api_request_body_limit: ApiRequestBodyLimit,
     pub import_map: TImportMap,
+    pub engine_config: SandboxEngineConfig,
+    pub session_limits: SessionLimits,
+    /// Requests that pass a `kv_namespace` can use this store. As with the
+    /// rest of the config, this trusts requests not to use each other's
+    /// namespaces.
+    pub kv_store: Option<Arc<dyn KvStore>>,
+    /// Exposed to every evaluation as the `secrets` global.
+    pub secrets: Secrets,
+    /// Limits the items sent by the NDJSON handler.
+    pub stream_limits: StreamLimits,
+    /// `budget` is only the default, as requests can pass their own.
+    pub batch_limits: BatchLimits,
 }
 
 impl AllowRequestToConfigureSandbox {
     pub fn from_env() -> anyhow::Result<Self> {
+        if get_env::<CpuTime>("SANDBOX_CPU_TIME")?.is_some() {
+            anyhow::bail!(
+                "SANDBOX_CPU_TIME can't be used with SANDBOX_ALLOW_CONFIG_IN_REQUEST, as requests set their own cpu_fuel"
+            );
+        }
         Ok(Self {
             api_request_body_limit: api_request_body_limit_from_env()?,
             import_map: import_map_from_env()?,
+            engine_config: engine_config_from_env()?,
+            session_limits: session_limits_from_env()?,
+            kv_store: kv_store_from_env()?,
+            secrets: secrets_from_env()?,
+            stream_limits: stream_limits_from_env()?,
+            batch_limits: batch_limits_from_env()?,
         })
     }
 }
@@ -224,9 +530,38 @@ impl<TImportMap: CustomImportMap + Clone>
                 },
                 strip_typescript_types: request.config.sandbox_auto_strip_types,
                 filename: request.filename,
+                value_encoding: request.config.value_encoding,
+                result_limits: request.config.result_limits,
+                wall_time_limit: request.config.wall_time_limit,
+                output_sink: None,
+                stream: None,
+                determinism: request.config.determinism,
+                virtual_time: request.config.virtual_time,
+                kv: kv_config(
+                    self.kv_store.as_ref(),
+                    request.config.kv_namespace,
+                    request.config.kv_limits,
+                ),
+                secrets: self.secrets.clone(),
+                profile: request.config.profile,
             },
         }
     }
+    fn get_engine_config(&self) -> SandboxEngineConfig {
+        self.engine_config.clone()
+    }
+    fn get_session_limits(&self) -> SessionLimits {
+        self.session_limits.clone()
+    }
+    fn get_stream_limits(&self) -> StreamLimits {
+        self.stream_limits
+    }
+    fn get_batch_limits(&self) -> BatchLimits {
+        self.batch_limits
+    }
+    fn get_batch_budget(&self, requested: Option<BatchBudget>) -> BatchBudget {
+        requested.unwrap_or(self.batch_limits.budget)
+    }
 }
 
 fn api_request_body_limit_from_env() -> anyhow::Result<ApiRequestBodyLimit> {
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use axum::{
+    Json, Router,
+    extract::Path,
+    http::StatusCode,
+    routing::{delete, post},
+};
+use secure_js_sandbox::{CancellationToken, SandboxEngine, SandboxSession, SessionIdleTimeout};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    CustomSandboxServerConfig, EvaluateResponse,
+    server_config::{EvaluateInput, set_request_body_limit},
+};
+
+#[derive(Clone, Debug)]
+pub struct SessionLimits {
+    /// Sessions that have not been called for this long are closed.
+    pub idle_timeout: SessionIdleTimeout,
+    /// The maximum number of sessions that can be open at once.
+    pub max_sessions: usize,
+}
+
+impl Default for SessionLimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: SessionIdleTimeout::default(),
+            max_sessions: 100,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct CreateSessionResponse {
+    /// This is `null` if the initial call terminated the session.
+    pub session_id: Option<String>,
+    #[serde(flatten)]
+    pub response: EvaluateResponse,
+}
+
+type SessionError = (StatusCode, String);
+
+struct OpenSession {
+    session: tokio::sync::Mutex<SandboxSession>,
+    last_used: Mutex<Instant>,
+}
+
+impl OpenSession {
+    fn touch(&self) {
+        *lock(&self.last_used) = Instant::now();
+    }
+    fn is_idle(&self, limits: &SessionLimits) -> bool {
+        let Some(idle_timeout) = limits.idle_timeout.as_duration() else {
+            return false;
+        };
+        // Sessions that are currently being called are never idle.
+        self.session.try_lock().is_ok() && lock(&self.last_used).elapsed() >= idle_timeout
+    }
+}
+
+struct SessionRegistry {
+    sessions: Mutex<HashMap<String, Arc<OpenSession>>>,
+    limits: SessionLimits,
+}
+
+impl SessionRegistry {
+    fn get(&self, id: &str) -> Option<Arc<OpenSession>> {
+        lock(&self.sessions).get(id).cloned()
+    }
+    fn remove(&self, id: &str) -> Option<Arc<OpenSession>> {
+        lock(&self.sessions).remove(id)
+    }
+    fn evict_idle(&self) {
+        lock(&self.sessions).retain(|_, session| !session.is_idle(&self.limits));
+    }
+    fn check_capacity(&self) -> Result<(), SessionError> {
+        self.evict_idle();
+        if lock(&self.sessions).len() >= self.limits.max_sessions {
+            return Err(too_many_sessions());
+        }
+        Ok(())
+    }
+    fn insert(&self, session: SandboxSession) -> Result<String, SessionError> {
+        let mut sessions = lock(&self.sessions);
+        if sessions.len() >= self.limits.max_sessions {
+            return Err(too_many_sessions());
+        }
+        let id = uuid::Uuid::new_v4().to_string();
+        sessions.insert(
+            id.clone(),
+            Arc::new(OpenSession {
+                session: tokio::sync::Mutex::new(session),
+                last_used: Mutex::new(Instant::now()),
+            }),
+        );
+        Ok(id)
+    }
+}
+
+pub async fn create_sessions_router<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+    T: Clone + Send + Sync + 'static,
+>(
+    config: TConfig,
+    engine: Arc<SandboxEngine>,
+) -> anyhow::Result<Router<T>> {
+    let limit = config.get_api_request_body_limit();
+    let registry = Arc::new(SessionRegistry {
+        sessions: Mutex::default(),
+        limits: config.get_session_limits(),
+    });
+    spawn_idle_session_eviction(&registry);
+    let config = Arc::new(config);
+
+    let create = {
+        let registry = registry.clone();
+        let config = config.clone();
+        post(async move |Json(request): Json<TRequest>| {
+            create_session(&config, request, &engine, &registry)
+                .await
+                .map(Json)
+        })
+    };
+    let evaluate = {
+        let registry = registry.clone();
+        post(
+            async move |Path(id): Path<String>, Json(request): Json<TRequest>| {
+                evaluate_in_session(&config, &id, request, &registry)
+                    .await
+                    .map(Json)
+            },
+        )
+    };
+    let close = delete(async move |Path(id): Path<String>| -> StatusCode {
+        match registry.remove(&id) {
+            Some(_) => StatusCode::NO_CONTENT,
+            None => StatusCode::NOT_FOUND,
+        }
+    });
+
+    Ok(Router::new()
+        .route("/sessions", set_request_body_limit(create, limit))
+        .route(
+            "/sessions/{id}/evaluate",
+            set_request_body_limit(evaluate, limit),
+        )
+        .route("/sessions/{id}", close))
+}
+
+/// Open a session, and run the code from the request in it.
+async fn create_session<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    config: &TConfig,
+    request: TRequest,
+    engine: &SandboxEngine,
+    registry: &SessionRegistry,
+) -> Result<CreateSessionResponse, SessionError> {
+    registry.check_capacity()?;
+    let EvaluateInput {
+        code,
+        parameters,
+        config,
+    } = config.get_evaluate_input(request);
+    let mut session = engine
+        .open_session(config)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let initial_cpu_fuel = session.fuel_remaining();
+    let cancel = CancellationToken::new();
+    let _cancel_on_drop = cancel.clone().drop_guard();
+    let result = session
+        .evaluate_cancellable(&code, &parameters, &cancel)
+        .await;
+    let response = EvaluateResponse::from_result(result, initial_cpu_fuel);
+    let session_id = if session.is_terminated() {
+        None
+    } else {
+        Some(registry.insert(session)?)
+    };
+    Ok(CreateSessionResponse {
+        session_id,
+        response,
+    })
+}
+
+/// Run the code from the request in an existing session. Only the `code`
+/// and `parameters` from the request are used, the sandbox config is fixed
+/// when the session is created.
+async fn evaluate_in_session<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    config: &TConfig,
+    id: &str,
+    request: TRequest,
+    registry: &SessionRegistry,
+) -> Result<EvaluateResponse, SessionError> {
+    let open_session = registry
+        .get(id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Session not found".to_string()))?;
+    let EvaluateInput {
+        code, parameters, ..
+    } = config.get_evaluate_input(request);
+    let mut session = open_session.session.lock().await;
+    let initial_cpu_fuel = session.fuel_remaining();
+    let cancel = CancellationToken::new();
+    let _cancel_on_drop = cancel.clone().drop_guard();
+    let result = session
+        .evaluate_cancellable(&code, &parameters, &cancel)
+        .await;
+    if session.is_terminated() {
+        registry.remove(id);
+    }
+    open_session.touch();
+    Ok(EvaluateResponse::from_result(result, initial_cpu_fuel))
+}
+
+fn spawn_idle_session_eviction(registry: &Arc<SessionRegistry>) {
+    let Some(idle_timeout) = registry.limits.idle_timeout.as_duration() else {
+        return;
+    };
+    let registry = Arc::downgrade(registry);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(idle_timeout / 2);
+        loop {
+            interval.tick().await;
+            // Stop once the router has been dropped.
+            let Some(registry) = registry.upgrade() else {
+                return;
+            };
+            registry.evict_idle();
+        }
+    });
+}
+
+fn too_many_sessions() -> SessionError {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Too many open sessions".to_string(),
+    )
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,95 @@
+//! Compares evaluating a TypeScript module with an import against invoking it
+//! after `SandboxEngine::parse`.
+//!
+//!   cargo bench --package secure_js_sandbox --bench parsed_script
+#![deny(warnings, clippy::all, clippy::pedantic, clippy::unwrap_used)]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use secure_js_sandbox::{
+    EvaluateMode, ImportMap, SandboxConfig, SandboxEngine, SandboxEvaluationResult,
+    StaticImportSource,
+};
+
+const RUNS: u32 = 50;
+
+/// The main module is TypeScript, and imports a module that is large enough
+/// for loading and compiling it to take a noticeable time.
+const CODE: &str = "import { f0, f199 } from 'dependency';
+export function run(a: number, b: number): number {
+  return f0(a, b) + f199(a, b);
+}";
+
+fn dependency() -> String {
+    let mut code = String::new();
+    for i in 0..200 {
+        code.push_str(&format!(
+            "export function f{i}(a, b) {{ return a * {i} + b; }}\n"
+        ));
+    }
+    code
+}
+
+fn check(result: SandboxEvaluationResult) -> anyhow::Result<()> {
+    match result.result {
+        Ok(value) if value == serde_json::json!(1990) => Ok(()),
+        Ok(value) => anyhow::bail!("Unexpected result: {value:?}"),
+        Err(err) => anyhow::bail!("Evaluation failed: {err}"),
+    }
+}
+
+async fn time<F: Future<Output = anyhow::Result<()>>>(
+    name: &str,
+    mut run: impl FnMut() -> F,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        run().await?;
+    }
+    let per_run = start.elapsed() / RUNS;
+    println!("{name}: {:.2}ms per run", per_run.as_secs_f64() * 1000.0);
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("parsed-script-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let dependency_path = dir.join("dependency.js");
+    std::fs::write(&dependency_path, dependency())?;
+    let config = SandboxConfig {
+        imports: ImportMap::StaticImportMap(Arc::new(HashMap::from([(
+            "dependency".to_string(),
+            StaticImportSource::File(dependency_path),
+        )]))),
+        mode: EvaluateMode::ModuleMethod("run".into()),
+        strip_typescript_types: true,
+        ..Default::default()
+    };
+    let parameters = [serde_json::json!(10).into(), serde_json::json!(0).into()];
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(async {
+        let engine = SandboxEngine::new()?;
+        // Warm up, so the first run doesn't pay for the allocator's setup.
+        check(engine.evaluate(CODE, &parameters, config.clone()).await)?;
+        time("evaluate", || async {
+            check(engine.evaluate(CODE, &parameters, config.clone()).await)
+        })
+        .await?;
+        let parse_start = Instant::now();
+        let script = engine
+            .parse(CODE, config.clone())
+            .await
+            .map_err(|err| anyhow::anyhow!("Parsing failed: {err}"))?;
+        let parse_time = parse_start.elapsed();
+        println!("parse: {:.2}ms once", parse_time.as_secs_f64() * 1000.0);
+        time("ParsedScript::invoke", || async {
+            check(script.invoke(&parameters).await)
+        })
+        .await
+    });
+    std::fs::remove_dir_all(dir)?;
+    result
+}
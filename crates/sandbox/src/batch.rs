@@ -0,0 +1,61 @@
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::{SandboxEvaluationResult, SandboxValue};
+
+/// How the CPU fuel and wall time limits apply to
+/// `SandboxEngine::evaluate_batch`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum BatchBudget {
+    /// The limits apply to the batch as a whole, including loading the code.
+    /// Once an item runs out of fuel or time, the rest of the items fail with
+    /// `EvaluateError::SessionTerminated`.
+    #[default]
+    Aggregate,
+    /// Each item gets the full fuel and wall time limits. If an item runs out,
+    /// the code is loaded again in a fresh sandbox for the rest of the items.
+    /// This runs a module's top level again, along with its side effects
+    /// such as outbound requests. The output and usage of loading it again
+    /// are included in the result of the item that runs next.
+    ///
+    /// Memory is not reset between items. It can't be released once a
+    /// sandbox has grown, so the memory limit applies to all the items that
+    /// run in the same sandbox together, as with `Aggregate`.
+    PerItem,
+}
+
+impl FromStr for BatchBudget {
+    type Err = ();
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "AGGREGATE" => Ok(BatchBudget::Aggregate),
+            "PER_ITEM" => Ok(BatchBudget::PerItem),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A call to one of a module's exports, for
+/// `SandboxEngine::evaluate_module_calls`.
+#[derive(Clone, Debug)]
+pub struct ModuleCall {
+    pub method: Box<str>,
+    pub parameters: Vec<SandboxValue>,
+}
+
+/// A call in a batch. `method` is `None` to call a function expression.
+pub(crate) struct BatchCall {
+    pub method: Option<Box<str>>,
+    pub parameters: Vec<SandboxValue>,
+}
+
+pub struct SandboxBatchResult {
+    /// The result of loading the code, which includes any output from
+    /// evaluating a module's top level. Its `result` is `null` if the code
+    /// was loaded, and `items` is empty if it wasn't.
+    pub load: SandboxEvaluationResult,
+    /// The result for each list of parameters or `ModuleCall`, in order.
+    pub items: Vec<SandboxEvaluationResult>,
+}
@@ -4,10 +4,11 @@
 use std::{error::Error, time::Instant};
 use wasmtime::{Config, Engine};
 
-fn get_engine() -> Result<Engine, Box<dyn Error>> {
+fn get_engine(epoch_interruption: bool) -> Result<Engine, Box<dyn Error>> {
     let mut engine_config = Config::new();
     // engine_config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
     engine_config.consume_fuel(true);
+    engine_config.epoch_interruption(epoch_interruption);
 
     // An engine stores and configures global compilation settings like
     // optimization level, enabled wasm features, etc.
@@ -15,7 +16,11 @@ fn get_engine() -> Result<Engine, Box<dyn Error>> {
     Ok(engine)
 }
 
-fn compile(input_path: &str, output_path: &str) -> Result<(), Box<dyn Error>> {
+fn compile(
+    input_path: &str,
+    output_path: &str,
+    epoch_interruption: bool,
+) -> Result<(), Box<dyn Error>> {
     let start = Instant::now();
 
     eprintln!("Reading Module From File ({:?} elapsed)", start.elapsed());
@@ -24,7 +29,7 @@ fn compile(input_path: &str, output_path: &str) -> Result<(), Box<dyn Error>> {
     eprintln!("Compiling Module ({:?} elapsed)", start.elapsed());
     // An engine stores and configures global compilation settings like
     // optimization level, enabled wasm features, etc.
-    let engine = get_engine()?;
+    let engine = get_engine(epoch_interruption)?;
     let compiled_component = engine.precompile_component(&wasm_bytes)?;
 
     eprintln!("Writing Module To File ({:?} elapsed)", start.elapsed());
@@ -65,8 +70,54 @@ fn compile(input_path: &str, output_path: &str) -> Result<(), Box<dyn Error>> {
 //     Ok(())
 // }
 
+/// The guest's sources, and the copies that `sandbox/build.mts` keeps next to
+/// the artifacts it generates from them.
+const GUEST_SOURCES: [(&str, &str); 3] = [
+    (
+        "../../sandbox/sandbox-host-code.js",
+        "src/sandbox/sources/sandbox-host-code.js",
+    ),
+    (
+        "../../sandbox/wit/sandbox.wit",
+        "src/sandbox/sources/sandbox.wit",
+    ),
+    (
+        "../../sandbox/wit/deps/host.wit",
+        "src/sandbox/sources/host.wit",
+    ),
+];
+
+/// Fails if the sandbox hasn't been built, or was built from different
+/// sources, so that a checkout never silently runs a stale guest. The sources
+/// aren't available when only the crates are copied, e.g. in the Dockerfile,
+/// so then the artifacts are trusted as they are.
+fn check_guest_artifacts() -> Result<(), Box<dyn Error>> {
+    const BUILD_STEP: &str =
+        "Run `npm install && node --run build:sandbox` in the repository root to regenerate it";
+    if !std::path::Path::new("src/sandbox/sandbox.wasm").exists() {
+        return Err(format!("src/sandbox/sandbox.wasm is missing. {BUILD_STEP}.").into());
+    }
+    for (source, copy) in GUEST_SOURCES {
+        println!("cargo:rerun-if-changed={source}");
+        println!("cargo:rerun-if-changed={copy}");
+        let Ok(source_contents) = std::fs::read(source) else {
+            continue;
+        };
+        if std::fs::read(copy).ok().as_deref() != Some(source_contents.as_slice()) {
+            return Err(format!(
+                "src/sandbox/sandbox.wasm was not built from the current {source}. {BUILD_STEP}."
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    compile("src/sandbox/sandbox.wasm", "src/sandbox/sandbox.bin")?;
-    compile("src/tsutils/tsutils.wasm", "src/tsutils/tsutils.bin")?;
+    check_guest_artifacts()?;
+    // Only the sandbox has a wall time limit, so tsutils is compiled without
+    // epoch checks.
+    compile("src/sandbox/sandbox.wasm", "src/sandbox/sandbox.bin", true)?;
+    compile("src/tsutils/tsutils.wasm", "src/tsutils/tsutils.bin", false)?;
     Ok(())
 }
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use crate::{CpuFuel, CpuTime, EvaluateError, Phase, SandboxConfig, SandboxEngine};
+
+/// The fuel each calibration run is given.
+const CALIBRATION_FUEL: u64 = 50_000_000;
+const CALIBRATION_RUNS: usize = 5;
+/// A mix of loops, strings, objects and JSON. It never returns, so each run
+/// uses exactly `CALIBRATION_FUEL`.
+const CALIBRATION_CODE: &str = "function () {
+  const items = [];
+  for (let i = 0; ; i++) {
+    items.push(JSON.parse(JSON.stringify({ i, name: `item-${i}`, tags: [i % 7, i % 11] })));
+    if (items.length > 1000) {
+      items.length = 0;
+    }
+  }
+}";
+
+/// How fast sandboxes consume fuel on this machine, so that CPU limits can be
+/// given as a time rather than as fuel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuelCalibration {
+    pub fuel_per_second: u64,
+}
+
+impl FuelCalibration {
+    /// Run a benchmark in a few sandboxes, and take the median rate. Only the
+    /// time spent running the code counts, not creating the sandboxes. This
+    /// takes a fraction of a second, and the result varies from run to run
+    /// by a few percent.
+    pub async fn measure() -> anyhow::Result<Self> {
+        let engine = SandboxEngine::new()?;
+        let mut calibrations = Vec::with_capacity(CALIBRATION_RUNS);
+        for _ in 0..CALIBRATION_RUNS {
+            let result = engine
+                .evaluate(
+                    CALIBRATION_CODE,
+                    &[],
+                    SandboxConfig {
+                        cpu_fuel: CpuFuel(CALIBRATION_FUEL),
+                        ..Default::default()
+                    },
+                )
+                .await;
+            match result.result {
+                Err(EvaluateError::FuelExhausted) => {}
+                Err(err) => anyhow::bail!("Fuel calibration failed: {err}"),
+                Ok(_) => anyhow::bail!("Fuel calibration code returned"),
+            }
+            let Some(execute) = result
+                .phases
+                .iter()
+                .find(|usage| usage.phase == Phase::Execute)
+            else {
+                anyhow::bail!("Fuel calibration didn't execute any code");
+            };
+            calibrations.push(Self::from_usage(execute.fuel_consumed, execute.duration));
+        }
+        calibrations.sort_unstable_by_key(|calibration| calibration.fuel_per_second);
+        Ok(calibrations[CALIBRATION_RUNS / 2])
+    }
+
+    #[must_use]
+    pub fn from_usage(fuel: u64, duration: Duration) -> Self {
+        let fuel_per_second = u128::from(fuel) * 1_000_000_000 / duration.as_nanos().max(1);
+        Self {
+            fuel_per_second: u64::try_from(fuel_per_second).unwrap_or(u64::MAX),
+        }
+    }
+
+    /// The fuel that takes about `time` to consume on this machine.
+    #[must_use]
+    pub fn fuel_for(&self, time: CpuTime) -> CpuFuel {
+        let fuel = u128::from(self.fuel_per_second) * u128::from(u64::from(time)) / 1000;
+        CpuFuel(u64::try_from(fuel).unwrap_or(u64::MAX).max(1))
+    }
+}
+
+#[test]
+fn test_fuel_for() {
+    let calibration = FuelCalibration::from_usage(440_000_000, Duration::from_millis(100));
+    assert_eq!(calibration.fuel_per_second, 4_400_000_000);
+    assert_eq!(calibration.fuel_for(CpuTime(250)), CpuFuel(1_100_000_000));
+    assert_eq!(
+        FuelCalibration { fuel_per_second: 1 }.fuel_for(CpuTime(1)),
+        CpuFuel(1)
+    );
+}
@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::sync::Notify;
+use wasmtime::component::Resource;
+use wasmtime_wasi::ResourceTable;
+use wasmtime_wasi::clocks::{HostMonotonicClock, HostWallClock};
+use wasmtime_wasi::p2::{DynPollable, Pollable, subscribe};
+
+/// The clock behind `wasi:clocks/monotonic-clock`, which is used for
+/// `performance.now()` and timers. This replaces wasmtime-wasi's own
+/// implementation, so that timers can be fast-forwarded.
+pub(crate) enum MonotonicClock {
+    /// Timers wait for real time to pass.
+    Host(Box<dyn HostMonotonicClock>),
+    /// Timers fire as soon as the sandbox is idle.
+    Virtual(VirtualTime),
+}
+
+impl MonotonicClock {
+    pub fn real() -> Self {
+        MonotonicClock::Host(Box::new(RealMonotonicClock {
+            start: Instant::now(),
+        }))
+    }
+    pub fn now(&self) -> u64 {
+        match self {
+            MonotonicClock::Host(clock) => clock.now(),
+            MonotonicClock::Virtual(time) => time.now(),
+        }
+    }
+    pub fn resolution(&self) -> u64 {
+        match self {
+            MonotonicClock::Host(clock) => clock.resolution(),
+            MonotonicClock::Virtual(_) => 1,
+        }
+    }
+    /// Returns a pollable that is ready once the clock reaches `when`.
+    pub fn subscribe_instant(
+        &self,
+        table: &mut ResourceTable,
+        when: u64,
+    ) -> wasmtime::Result<Resource<DynPollable>> {
+        match self {
+            MonotonicClock::Host(clock) => {
+                let duration = Duration::from_nanos(when.saturating_sub(clock.now()));
+                let sleep = table.push(Sleep(tokio::time::Instant::now().checked_add(duration)))?;
+                subscribe(table, sleep)
+            }
+            MonotonicClock::Virtual(time) => {
+                let timer = table.push(time.timer(when))?;
+                subscribe(table, timer)
+            }
+        }
+    }
+}
+
+struct RealMonotonicClock {
+    start: Instant,
+}
+
+impl HostMonotonicClock for RealMonotonicClock {
+    fn resolution(&self) -> u64 {
+        1
+    }
+    fn now(&self) -> u64 {
+        u64::try_from(self.start.elapsed().as_nanos()).unwrap_or(u64::MAX)
+    }
+}
+
+/// `None` if the deadline is too far away to represent.
+struct Sleep(Option<tokio::time::Instant>);
+
+#[wasmtime_wasi::async_trait]
+impl Pollable for Sleep {
+    async fn ready(&mut self) {
+        match self.0 {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// A clock that only moves when the sandbox is waiting for timers and
+/// nothing else. It then jumps straight to the next timer, so code that
+/// waits for a timeout doesn't spend real time waiting.
+#[derive(Clone, Default)]
+pub(crate) struct VirtualTime(Arc<VirtualTimeState>);
+
+#[derive(Default)]
+struct VirtualTimeState {
+    inner: Mutex<VirtualTimeInner>,
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct VirtualTimeInner {
+    /// Nanoseconds since the sandbox started.
+    now: u64,
+    /// The number of timers waiting for each deadline.
+    timers: BTreeMap<u64, usize>,
+    /// Outbound requests waiting for headers, and response bodies that the
+    /// guest is waiting to read from. Time stands still until they complete.
+    pending_io: usize,
+}
+
+impl VirtualTime {
+    pub fn now(&self) -> u64 {
+        self.lock().now
+    }
+    /// Time stands still until the returned guard is dropped.
+    pub fn start_io(&self) -> PendingIo {
+        self.lock().pending_io += 1;
+        PendingIo(self.clone())
+    }
+    /// A wall clock that starts at `start`, as a duration since the Unix
+    /// epoch, and moves with this clock.
+    pub fn wall_clock(&self, start: Duration) -> VirtualWallClock {
+        VirtualWallClock {
+            start,
+            time: self.clone(),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, VirtualTimeInner> {
+        self.0.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn timer(&self, deadline: u64) -> VirtualTimer {
+        *self.lock().timers.entry(deadline).or_default() += 1;
+        VirtualTimer {
+            time: self.clone(),
+            deadline,
+        }
+    }
+    /// If the guest is blocked waiting on timers and there is no I/O in
+    /// flight, it can't do anything else until the next timer fires.
+    ///
+    /// `pollable.ready()` only checks whether the timer has fired, by polling
+    /// this once, so time mustn't move on the first poll. `poll` and `block`
+    /// poll again after it yields, and only then is time fast-forwarded.
+    async fn wait_until(&self, deadline: u64) {
+        let mut yielded = false;
+        loop {
+            // Created before checking the state, so no change is missed.
+            let changed = self.0.changed.notified();
+            {
+                let mut inner = self.lock();
+                if inner.now >= deadline {
+                    return;
+                }
+                if !yielded {
+                    drop(inner);
+                    yielded = true;
+                    tokio::task::yield_now().await;
+                    continue;
+                }
+                if inner.pending_io == 0 {
+                    let now = inner.now;
+                    let next = inner
+                        .timers
+                        .range(now.saturating_add(1)..)
+                        .next()
+                        .map_or(deadline, |(next, _)| *next);
+                    inner.now = next.min(deadline);
+                    drop(inner);
+                    self.0.changed.notify_waiters();
+                    continue;
+                }
+            }
+            changed.await;
+        }
+    }
+}
+
+pub(crate) struct PendingIo(VirtualTime);
+
+/// A response body that keeps time standing still while a read from it is
+/// waiting for data. A body the guest isn't reading from doesn't hold up
+/// timers, even if it is never read to the end or dropped.
+pub(crate) struct PendingIoBody<B> {
+    body: B,
+    time: VirtualTime,
+    reading: Option<PendingIo>,
+}
+
+impl<B> PendingIoBody<B> {
+    pub fn new(body: B, time: VirtualTime) -> Self {
+        Self {
+            body,
+            time,
+            reading: None,
+        }
+    }
+}
+
+impl<B: Body + Unpin> Body for PendingIoBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let result = Pin::new(&mut self.body).poll_frame(cx);
+        if result.is_pending() {
+            if self.reading.is_none() {
+                self.reading = Some(self.time.start_io());
+            }
+        } else {
+            self.reading = None;
+        }
+        result
+    }
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for PendingIo {
+    fn drop(&mut self) {
+        let mut inner = self.0.lock();
+        inner.pending_io = inner.pending_io.saturating_sub(1);
+        drop(inner);
+        self.0.0.changed.notify_waiters();
+    }
+}
+
+struct VirtualTimer {
+    time: VirtualTime,
+    deadline: u64,
+}
+
+#[wasmtime_wasi::async_trait]
+impl Pollable for VirtualTimer {
+    async fn ready(&mut self) {
+        self.time.wait_until(self.deadline).await;
+    }
+}
+
+impl Drop for VirtualTimer {
+    fn drop(&mut self) {
+        let mut inner = self.time.lock();
+        if let Some(count) = inner.timers.get_mut(&self.deadline) {
+            *count -= 1;
+            if *count == 0 {
+                inner.timers.remove(&self.deadline);
+            }
+        }
+    }
+}
+
+pub(crate) struct VirtualWallClock {
+    start: Duration,
+    time: VirtualTime,
+}
+
+impl HostWallClock for VirtualWallClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+    fn now(&self) -> Duration {
+        self.start + Duration::from_nanos(self.time.now())
+    }
+}
+
+#[tokio::test]
+async fn test_virtual_time_skips_to_next_timer() {
+    let time = VirtualTime::default();
+    let mut later = time.timer(5_000);
+    let mut sooner = time.timer(1_000);
+    sooner.ready().await;
+    assert_eq!(time.now(), 1_000);
+    drop(sooner);
+
+    // Time stands still while I/O is in flight.
+    let io = time.start_io();
+    let waiting = tokio::spawn(async move {
+        later.ready().await;
+    });
+    tokio::task::yield_now().await;
+    assert_eq!(time.now(), 1_000);
+    drop(io);
+    waiting.await.unwrap();
+    assert_eq!(time.now(), 5_000);
+}
+
+#[tokio::test]
+async fn test_virtual_time_only_moves_while_blocked() {
+    let time = VirtualTime::default();
+    let mut timer = time.timer(1_000);
+    // `pollable.ready()` polls the timer once, which doesn't move time.
+    let mut ready = timer.ready();
+    let mut cx = Context::from_waker(std::task::Waker::noop());
+    assert!(ready.as_mut().poll(&mut cx).is_pending());
+    drop(ready);
+    assert_eq!(time.now(), 0);
+    timer.ready().await;
+    assert_eq!(time.now(), 1_000);
+}
+
+#[tokio::test]
+async fn test_virtual_time_waits_for_response_body() {
+    /// A body whose data never arrives.
+    struct Stalled;
+    impl Body for Stalled {
+        type Data = bytes::Bytes;
+        type Error = std::convert::Infallible;
+        fn poll_frame(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Pending
+        }
+    }
+
+    let time = VirtualTime::default();
+    let mut body = PendingIoBody::new(Stalled, time.clone());
+    let mut cx = Context::from_waker(std::task::Waker::noop());
+    assert!(Pin::new(&mut body).poll_frame(&mut cx).is_pending());
+    assert_eq!(time.lock().pending_io, 1);
+    drop(body);
+    assert_eq!(time.lock().pending_io, 0);
+}
+
+#[tokio::test]
+async fn test_virtual_time_ignores_unread_body() {
+    use http_body_util::{BodyExt, Full};
+
+    let time = VirtualTime::default();
+    let body = PendingIoBody::new(Full::new(bytes::Bytes::from("body")), time.clone());
+    // The guest has the headers, but isn't reading the body.
+    let mut timer = time.timer(1_000);
+    timer.ready().await;
+    assert_eq!(time.now(), 1_000);
+    body.collect().await.unwrap();
+    assert_eq!(time.lock().pending_io, 0);
+}
@@ -0,0 +1,155 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use wasmtime_wasi::WasiCtxBuilder;
+use wasmtime_wasi::clocks::{HostMonotonicClock, HostWallClock};
+
+use crate::clocks::MonotonicClock;
+
+/// How far the clocks move each time they are read in `ClockMode::Virtual`.
+const VIRTUAL_CLOCK_STEP: Duration = Duration::from_micros(1);
+
+/// Replaces the sandbox's clocks and random number generators, so that
+/// `Date.now()`, `performance.now()`, `Math.random()` and
+/// `crypto.getRandomValues()` return the same values every time the same
+/// code is evaluated with the same seed.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Determinism {
+    /// Seed for the random number generators. If this is `None` a random seed
+    /// is used, which is returned in `SandboxEvaluationResult::seed` so that
+    /// the evaluation can be replayed.
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// The time reported by the wall clock when the sandbox starts, in
+    /// milliseconds since the Unix epoch. Ignored in `ClockMode::Real`.
+    #[serde(default)]
+    pub start_time_ms: u64,
+    #[serde(default)]
+    pub clock: ClockMode,
+}
+
+impl Determinism {
+    /// The wall clock's time when the sandbox starts, or `None` if it uses the
+    /// host's clocks.
+    pub(crate) fn start_time(&self) -> Option<Duration> {
+        match self.clock {
+            ClockMode::Fixed | ClockMode::Virtual => {
+                Some(Duration::from_millis(self.start_time_ms))
+            }
+            ClockMode::Real => None,
+        }
+    }
+
+    /// Install the clocks and random number generators in `builder`, and
+    /// return the seed that was used along with the monotonic clock.
+    pub(crate) fn apply(&self, builder: &mut WasiCtxBuilder) -> (u64, MonotonicClock) {
+        // Random seeds are kept within the range that JSON numbers can
+        // represent exactly in JavaScript, so they can be passed back in.
+        let seed = self.seed.unwrap_or_else(|| rand::random::<u64>() >> 11);
+        let step = match self.clock {
+            ClockMode::Fixed => Some(Duration::ZERO),
+            ClockMode::Virtual => Some(VIRTUAL_CLOCK_STEP),
+            ClockMode::Real => None,
+        };
+        let monotonic_clock = match step {
+            Some(step) => {
+                let clock = DeterministicClock {
+                    start: Duration::from_millis(self.start_time_ms),
+                    elapsed_nanos: Arc::default(),
+                    step,
+                };
+                builder
+                    .wall_clock(clock.clone())
+                    .monotonic_clock(clock.clone());
+                MonotonicClock::Host(Box::new(clock))
+            }
+            None => MonotonicClock::real(),
+        };
+        builder
+            .secure_random(StdRng::seed_from_u64(seed))
+            // Use a different stream, so the two sources don't return the
+            // same numbers.
+            .insecure_random(StdRng::seed_from_u64(!seed))
+            .insecure_random_seed(u128::from(seed));
+        (seed, monotonic_clock)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClockMode {
+    /// The clocks never move.
+    #[default]
+    Fixed,
+    /// The clocks move forward by a microsecond each time they are read, so
+    /// code that waits for time to pass still makes progress.
+    Virtual,
+    /// The clocks aren't replaced, so only the random number generators are
+    /// deterministic.
+    Real,
+}
+
+impl FromStr for ClockMode {
+    type Err = ();
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "FIXED" => Ok(ClockMode::Fixed),
+            "VIRTUAL" => Ok(ClockMode::Virtual),
+            "REAL" => Ok(ClockMode::Real),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Used as both the wall clock and the monotonic clock, so they stay in step.
+#[derive(Clone)]
+struct DeterministicClock {
+    start: Duration,
+    elapsed_nanos: Arc<AtomicU64>,
+    step: Duration,
+}
+
+impl DeterministicClock {
+    fn read(&self) -> u64 {
+        let step = u64::try_from(self.step.as_nanos()).unwrap_or(u64::MAX);
+        self.elapsed_nanos.fetch_add(step, Ordering::Relaxed)
+    }
+}
+
+impl HostWallClock for DeterministicClock {
+    fn resolution(&self) -> Duration {
+        VIRTUAL_CLOCK_STEP
+    }
+    fn now(&self) -> Duration {
+        self.start + Duration::from_nanos(self.read())
+    }
+}
+
+impl HostMonotonicClock for DeterministicClock {
+    fn resolution(&self) -> u64 {
+        1_000
+    }
+    fn now(&self) -> u64 {
+        self.read()
+    }
+}
+
+#[test]
+fn test_virtual_clock() {
+    let clock = DeterministicClock {
+        start: Duration::from_secs(10),
+        elapsed_nanos: Arc::default(),
+        step: VIRTUAL_CLOCK_STEP,
+    };
+    assert_eq!(HostMonotonicClock::now(&clock), 0);
+    assert_eq!(HostMonotonicClock::now(&clock), 1_000);
+    assert_eq!(
+        HostWallClock::now(&clock),
+        Duration::from_secs(10) + Duration::from_micros(2)
+    );
+}
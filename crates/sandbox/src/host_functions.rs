@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use serde_json::Value;
+
+pub type HostFunctionFuture = Pin<Box<dyn Future<Output = anyhow::Result<Value>> + Send>>;
+
+/// Functions that JavaScript code can call using the `host` global, or by
+/// importing them from the `sandbox:host` module. Arguments and results are
+/// passed as JSON.
+///
+/// The sandbox is paused while a host function runs, so these should return
+/// quickly.
+pub trait HostFunctions: Send + Sync + 'static {
+    /// Start a call to the function named `name`, or return `None` if there
+    /// is no such function.
+    fn call(&self, name: &str, args: Vec<Value>) -> Option<HostFunctionFuture>;
+}
+
+type BoxedHostFunction = Box<dyn Fn(Vec<Value>) -> HostFunctionFuture + Send + Sync>;
+
+/// A set of named async functions.
+#[derive(Default)]
+pub struct HostFunctionRegistry {
+    functions: HashMap<String, BoxedHostFunction>,
+}
+
+impl HostFunctionRegistry {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn register<F, Fut>(&mut self, name: impl Into<String>, function: F) -> &mut Self
+    where
+        F: Fn(Vec<Value>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<Value>> + Send + 'static,
+    {
+        self.functions
+            .insert(name.into(), Box::new(move |args| Box::pin(function(args))));
+        self
+    }
+}
+
+impl HostFunctions for HostFunctionRegistry {
+    fn call(&self, name: &str, args: Vec<Value>) -> Option<HostFunctionFuture> {
+        self.functions.get(name).map(|function| function(args))
+    }
+}
+
+/// A call from the sandbox to a host function.
+#[derive(Clone, Debug)]
+pub struct HostCall {
+    pub name: String,
+    /// False if the function returned an error or doesn't exist.
+    pub success: bool,
+    pub duration: Duration,
+}
@@ -18,6 +18,7 @@ use wasmtime_wasi_http::p2::hyper_request_error;
 use wasmtime_wasi_http::p2::types::{IncomingResponse, OutgoingRequestConfig};
 
 use crate::ip_utils::IpUtils;
+use crate::secrets::Secrets;
 use crate::shared_vec::SharedVec;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -177,6 +178,7 @@ pub(crate) async fn send_request_handler(
     }: wasmtime_wasi_http::p2::types::OutgoingRequestConfig,
     http_mode: &impl CustomHttpMode,
     requests: SharedVec<OutboundRequest>,
+    secrets: &Secrets,
 ) -> Result<wasmtime_wasi_http::p2::types::IncomingResponse, ErrorCode> {
     let mut redirect_count: u8 = 0;
     let mut next_request = Some(request);
@@ -196,6 +198,14 @@ pub(crate) async fn send_request_handler(
             return Err(ErrorCode::DestinationNotFound);
         }
 
+        // Only once the destination is allowed, and again for each redirect,
+        // so credentials are never sent to hosts they aren't bound to.
+        if let Some(host) = request.uri().host().map(str::to_string) {
+            secrets
+                .substitute_headers(&host, request.headers_mut())
+                .map_err(|err| ErrorCode::InternalError(Some(err)))?;
+        }
+
         let authority: String = if let Some(authority) = request.uri().authority() {
             if authority.port().is_some() {
                 authority.to_string()
@@ -0,0 +1,154 @@
+use std::fmt;
+
+use serde::Serialize;
+
+/// A value thrown by the JavaScript code.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct JavaScriptError {
+    /// e.g. "TypeError". This is `None` if the value thrown was not an `Error`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub message: String,
+    /// Stack frames from the sandbox's own code are not included.
+    pub stack: Vec<StackFrame>,
+    /// The value that was thrown, if it was not an `Error` and can be
+    /// serialized as JSON.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cause: Option<Box<JavaScriptError>>,
+}
+
+impl JavaScriptError {
+    #[must_use]
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            name: None,
+            message: message.into(),
+            stack: vec![],
+            value: None,
+            cause: None,
+        }
+    }
+}
+
+impl fmt::Display for JavaScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.stack {
+            write!(f, "\n  {frame}")?;
+        }
+        if let Some(cause) = &self.cause {
+            write!(f, "\nCaused by: {cause}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct StackFrame {
+    /// `None` for anonymous functions and top level module code.
+    pub function: Option<String>,
+    pub filename: String,
+    pub line: u32,
+    pub column: u32,
+    /// True if this frame was reached by resuming an async function.
+    pub async_boundary: bool,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.async_boundary {
+            write!(f, "async*")?;
+        }
+        write!(
+            f,
+            "{}@{}:{}:{}",
+            self.function.as_deref().unwrap_or(""),
+            self.filename,
+            self.line,
+            self.column
+        )
+    }
+}
+
+/// Parse a SpiderMonkey stack trace, where each line looks like
+/// `async*name@filename:line:column`. Frames from the sandbox's own code,
+/// and lines that can't be parsed, are skipped.
+pub(crate) fn parse_stack(stack: &str) -> Vec<StackFrame> {
+    stack
+        .lines()
+        .filter_map(parse_stack_frame)
+        .filter(|frame| {
+            !frame.filename.ends_with("sandbox-host-code.js")
+                && !frame.filename.ends_with("sources/initializer.js")
+        })
+        .collect()
+}
+
+fn parse_stack_frame(line: &str) -> Option<StackFrame> {
+    let (async_boundary, line) = match line.trim().strip_prefix("async*") {
+        Some(line) => (true, line),
+        None => (false, line.trim()),
+    };
+    let (function, location) = line.split_once('@')?;
+    let (location, column) = location.rsplit_once(':')?;
+    let (filename, line) = location.rsplit_once(':')?;
+    Some(StackFrame {
+        function: (!function.is_empty()).then(|| function.to_string()),
+        filename: filename.to_string(),
+        line: line.parse().ok()?,
+        column: column.parse().ok()?,
+        async_boundary,
+    })
+}
+
+#[test]
+fn test_parse_stack() {
+    let stack = "c@error_test.ts:15:15\n\
+        @http://localhost:3001/fib.js:2:9\n\
+        evaluateCompiledModule@sandbox-host-code.js:96:16\n\
+        async*run@error_test.ts:5:15\n\
+        not a stack frame\n";
+    assert_eq!(
+        parse_stack(stack),
+        vec![
+            StackFrame {
+                function: Some("c".to_string()),
+                filename: "error_test.ts".to_string(),
+                line: 15,
+                column: 15,
+                async_boundary: false,
+            },
+            StackFrame {
+                function: None,
+                filename: "http://localhost:3001/fib.js".to_string(),
+                line: 2,
+                column: 9,
+                async_boundary: false,
+            },
+            StackFrame {
+                function: Some("run".to_string()),
+                filename: "error_test.ts".to_string(),
+                line: 5,
+                column: 15,
+                async_boundary: true,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_display_javascript_error() {
+    let error = JavaScriptError {
+        name: Some("Error".to_string()),
+        message: "Outer".to_string(),
+        stack: parse_stack("async*run@input.js:5:15\n"),
+        value: None,
+        cause: Some(Box::new(JavaScriptError::new("Inner"))),
+    };
+    assert_eq!(
+        error.to_string(),
+        "Outer\n  async*run@input.js:5:15\nCaused by: Inner"
+    );
+}
@@ -0,0 +1,577 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use serde::Deserialize;
+
+use crate::{MemorySizeBytes, ResourceLimit};
+
+pub type KvFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+/// Durable key-value storage for sandboxes. Each sandbox can only see the
+/// keys in its own namespace.
+///
+/// The sandbox is paused while an operation runs, so implementations
+/// shouldn't block the async runtime.
+pub trait KvStore: Send + Sync + 'static {
+    fn get<'a>(&'a self, namespace: &'a str, key: &'a str) -> KvFuture<'a, Option<String>>;
+    /// Fails if the namespace would then have more than `limits.max_keys`
+    /// keys, or `limits.max_stored_bytes` bytes of keys and values.
+    fn set<'a>(
+        &'a self,
+        namespace: &'a str,
+        key: &'a str,
+        value: String,
+        limits: KvLimits,
+    ) -> KvFuture<'a, ()>;
+    /// Returns false if the key didn't exist.
+    fn delete<'a>(&'a self, namespace: &'a str, key: &'a str) -> KvFuture<'a, bool>;
+    /// Returns the keys starting with `prefix`, in order.
+    fn list<'a>(&'a self, namespace: &'a str, prefix: &'a str) -> KvFuture<'a, Vec<String>>;
+}
+
+/// Gives a sandbox access to a namespace in a `KvStore`.
+#[derive(Clone)]
+pub struct KvConfig {
+    pub store: Arc<dyn KvStore>,
+    pub namespace: String,
+    pub limits: KvLimits,
+}
+
+/// `max_keys` and `max_stored_bytes` apply to everything stored in the
+/// namespace. The rest apply to each evaluation, or to a session as a whole.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct KvLimits {
+    /// The maximum number of `get`, `set`, `delete` and `list` calls.
+    #[serde(default = "default_max_operations")]
+    pub max_operations: ResourceLimit,
+    /// The maximum total size of the keys and values read and written.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: MemorySizeBytes,
+    /// The maximum size of a single value.
+    #[serde(default = "default_max_value_bytes")]
+    pub max_value_bytes: MemorySizeBytes,
+    /// The maximum number of keys stored in the namespace.
+    #[serde(default = "default_max_keys")]
+    pub max_keys: ResourceLimit,
+    /// The maximum total size of the keys and values stored in the namespace.
+    #[serde(default = "default_max_stored_bytes")]
+    pub max_stored_bytes: MemorySizeBytes,
+}
+impl Default for KvLimits {
+    fn default() -> Self {
+        Self {
+            max_operations: default_max_operations(),
+            max_bytes: default_max_bytes(),
+            max_value_bytes: default_max_value_bytes(),
+            max_keys: default_max_keys(),
+            max_stored_bytes: default_max_stored_bytes(),
+        }
+    }
+}
+
+fn default_max_operations() -> ResourceLimit {
+    ResourceLimit(1_000)
+}
+fn default_max_bytes() -> MemorySizeBytes {
+    MemorySizeBytes(1024 * 1024)
+}
+fn default_max_value_bytes() -> MemorySizeBytes {
+    MemorySizeBytes(64 * 1024)
+}
+fn default_max_keys() -> ResourceLimit {
+    ResourceLimit(10_000)
+}
+fn default_max_stored_bytes() -> MemorySizeBytes {
+    MemorySizeBytes(10 * 1024 * 1024)
+}
+
+/// Tracks a sandbox's usage of its `KvConfig`.
+pub(crate) struct KvState {
+    config: KvConfig,
+    operations: usize,
+    bytes: usize,
+}
+
+impl KvState {
+    pub fn new(config: KvConfig) -> Self {
+        Self {
+            config,
+            operations: 0,
+            bytes: 0,
+        }
+    }
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>, String> {
+        self.start_operation(key.len())?;
+        let value = self
+            .config
+            .store
+            .get(&self.config.namespace, key)
+            .await
+            .map_err(|err| err.to_string())?;
+        self.add_bytes(value.as_ref().map_or(0, String::len))?;
+        Ok(value)
+    }
+    pub async fn set(&mut self, key: &str, value: String) -> Result<(), String> {
+        if value.len() > usize::from(self.config.limits.max_value_bytes) {
+            return Err(format!(
+                "KV value is larger than the limit of {} bytes",
+                usize::from(self.config.limits.max_value_bytes)
+            ));
+        }
+        self.start_operation(key.len().saturating_add(value.len()))?;
+        self.config
+            .store
+            .set(&self.config.namespace, key, value, self.config.limits)
+            .await
+            .map_err(|err| err.to_string())
+    }
+    pub async fn delete(&mut self, key: &str) -> Result<bool, String> {
+        self.start_operation(key.len())?;
+        self.config
+            .store
+            .delete(&self.config.namespace, key)
+            .await
+            .map_err(|err| err.to_string())
+    }
+    pub async fn list(&mut self, prefix: &str) -> Result<Vec<String>, String> {
+        self.start_operation(prefix.len())?;
+        let keys = self
+            .config
+            .store
+            .list(&self.config.namespace, prefix)
+            .await
+            .map_err(|err| err.to_string())?;
+        self.add_bytes(keys.iter().map(String::len).sum())?;
+        Ok(keys)
+    }
+    fn start_operation(&mut self, bytes: usize) -> Result<(), String> {
+        self.operations = self.operations.saturating_add(1);
+        if self.operations > usize::from(self.config.limits.max_operations) {
+            return Err("KV operation limit exceeded".to_string());
+        }
+        self.add_bytes(bytes)
+    }
+    fn add_bytes(&mut self, bytes: usize) -> Result<(), String> {
+        self.bytes = self.bytes.saturating_add(bytes);
+        if self.bytes > usize::from(self.config.limits.max_bytes) {
+            return Err("KV byte limit exceeded".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// The keys and values stored in a namespace.
+#[derive(Clone, Copy, Default)]
+struct KvUsage {
+    keys: usize,
+    stored_bytes: usize,
+}
+
+impl KvUsage {
+    fn add(&mut self, key: &str, value_len: usize) {
+        self.keys += 1;
+        self.stored_bytes += key.len() + value_len;
+    }
+    fn remove(&mut self, key: &str, value_len: usize) {
+        self.keys = self.keys.saturating_sub(1);
+        self.stored_bytes = self.stored_bytes.saturating_sub(key.len() + value_len);
+    }
+    /// The usage after replacing the value of `key`, which was `old_len`
+    /// bytes long if it existed, with `new_len` bytes.
+    fn replace(
+        mut self,
+        key: &str,
+        old_len: Option<usize>,
+        new_len: usize,
+        limits: KvLimits,
+    ) -> anyhow::Result<Self> {
+        if let Some(old_len) = old_len {
+            self.remove(key, old_len);
+        }
+        self.add(key, new_len);
+        if self.keys > usize::from(limits.max_keys) {
+            anyhow::bail!("KV key limit exceeded");
+        }
+        if self.stored_bytes > usize::from(limits.max_stored_bytes) {
+            anyhow::bail!("KV storage limit exceeded");
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Default)]
+struct Namespace {
+    values: BTreeMap<String, String>,
+    usage: KvUsage,
+}
+
+/// Keeps everything in memory, so nothing is kept when the process exits.
+#[derive(Default)]
+pub struct MemoryKvStore {
+    namespaces: Mutex<HashMap<String, Namespace>>,
+}
+
+impl MemoryKvStore {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Namespace>> {
+        self.namespaces
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+    fn set_now(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: String,
+        limits: KvLimits,
+    ) -> anyhow::Result<()> {
+        let mut namespaces = self.lock();
+        let namespace = namespaces.entry(namespace.to_string()).or_default();
+        let old_len = namespace.values.get(key).map(String::len);
+        namespace.usage = namespace.usage.replace(key, old_len, value.len(), limits)?;
+        namespace.values.insert(key.to_string(), value);
+        Ok(())
+    }
+    fn delete_now(&self, namespace: &str, key: &str) -> bool {
+        let mut namespaces = self.lock();
+        let Some(namespace) = namespaces.get_mut(namespace) else {
+            return false;
+        };
+        match namespace.values.remove(key) {
+            Some(value) => {
+                namespace.usage.remove(key, value.len());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl KvStore for MemoryKvStore {
+    fn get<'a>(&'a self, namespace: &'a str, key: &'a str) -> KvFuture<'a, Option<String>> {
+        let value = self
+            .lock()
+            .get(namespace)
+            .and_then(|namespace| namespace.values.get(key).cloned());
+        Box::pin(async move { anyhow::Ok(value) })
+    }
+    fn set<'a>(
+        &'a self,
+        namespace: &'a str,
+        key: &'a str,
+        value: String,
+        limits: KvLimits,
+    ) -> KvFuture<'a, ()> {
+        let result = self.set_now(namespace, key, value, limits);
+        Box::pin(async move { result })
+    }
+    fn delete<'a>(&'a self, namespace: &'a str, key: &'a str) -> KvFuture<'a, bool> {
+        let deleted = self.delete_now(namespace, key);
+        Box::pin(async move { anyhow::Ok(deleted) })
+    }
+    fn list<'a>(&'a self, namespace: &'a str, prefix: &'a str) -> KvFuture<'a, Vec<String>> {
+        let keys = self
+            .lock()
+            .get(namespace)
+            .map(|namespace| {
+                namespace
+                    .values
+                    .range(prefix.to_string()..)
+                    .take_while(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Box::pin(async move { anyhow::Ok(keys) })
+    }
+}
+
+/// The longest key `FileKvStore` can store, as each key is hex encoded into a
+/// file name, which is usually limited to 255 bytes.
+const MAX_FILE_KEY_BYTES: usize = 120;
+
+/// Stores each namespace as a directory, with a file for each key. Files are
+/// replaced atomically, so a crash never leaves a value half written.
+///
+/// The usage of each namespace is counted when it is first used, and then
+/// tracked in memory, so only one process should use the directory at once.
+pub struct FileKvStore {
+    dir: PathBuf,
+    /// Writes to a namespace are serialized, so its usage stays accurate.
+    usage: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<KvUsage>>>>>,
+}
+
+impl FileKvStore {
+    pub fn new(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            usage: Mutex::default(),
+        })
+    }
+    fn namespace_dir(&self, namespace: &str) -> anyhow::Result<PathBuf> {
+        if namespace.is_empty()
+            || !namespace
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!("Invalid KV namespace: {namespace}");
+        }
+        Ok(self.dir.join(namespace))
+    }
+    fn path(&self, namespace: &str, key: &str) -> anyhow::Result<PathBuf> {
+        if key.len() > MAX_FILE_KEY_BYTES {
+            anyhow::bail!("KV key is longer than the limit of {MAX_FILE_KEY_BYTES} bytes");
+        }
+        Ok(self.namespace_dir(namespace)?.join(encode_file_name(key)))
+    }
+    fn namespace_usage(&self, namespace: &str) -> Arc<tokio::sync::Mutex<Option<KvUsage>>> {
+        self.usage
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(namespace.to_string())
+            .or_default()
+            .clone()
+    }
+    /// Runs `update` with the namespace's usage, and no other writes to the
+    /// namespace in progress.
+    async fn update<T: Send + 'static>(
+        &self,
+        namespace: &str,
+        update: impl FnOnce(&mut KvUsage) -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let dir = self.namespace_dir(namespace)?;
+        let lock = self.namespace_usage(namespace);
+        let mut usage = lock.lock().await;
+        let current = *usage;
+        let (result, current) = tokio::task::spawn_blocking(move || {
+            let mut current = match current {
+                Some(current) => current,
+                None => read_usage(&dir)?,
+            };
+            let result = update(&mut current);
+            anyhow::Ok((result, current))
+        })
+        .await??;
+        *usage = Some(current);
+        result
+    }
+}
+
+impl KvStore for FileKvStore {
+    fn get<'a>(&'a self, namespace: &'a str, key: &'a str) -> KvFuture<'a, Option<String>> {
+        Box::pin(async move {
+            let path = self.path(namespace, key)?;
+            tokio::task::spawn_blocking(move || match std::fs::read_to_string(path) {
+                Ok(value) => anyhow::Ok(Some(value)),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            })
+            .await?
+        })
+    }
+    fn set<'a>(
+        &'a self,
+        namespace: &'a str,
+        key: &'a str,
+        value: String,
+        limits: KvLimits,
+    ) -> KvFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.path(namespace, key)?;
+            let key = key.to_string();
+            self.update(namespace, move |usage| {
+                let old_len = file_len(&path)?;
+                let new_usage = usage.replace(&key, old_len, value.len(), limits)?;
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let temp_path = path.with_extension("tmp");
+                std::fs::write(&temp_path, value)?;
+                std::fs::rename(temp_path, path)?;
+                *usage = new_usage;
+                Ok(())
+            })
+            .await
+        })
+    }
+    fn delete<'a>(&'a self, namespace: &'a str, key: &'a str) -> KvFuture<'a, bool> {
+        Box::pin(async move {
+            let path = self.path(namespace, key)?;
+            let key = key.to_string();
+            self.update(namespace, move |usage| {
+                let Some(old_len) = file_len(&path)? else {
+                    return Ok(false);
+                };
+                std::fs::remove_file(path)?;
+                usage.remove(&key, old_len);
+                Ok(true)
+            })
+            .await
+        })
+    }
+    fn list<'a>(&'a self, namespace: &'a str, prefix: &'a str) -> KvFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let dir = self.namespace_dir(namespace)?;
+            let prefix = prefix.to_string();
+            tokio::task::spawn_blocking(move || {
+                let mut keys: Vec<String> = read_keys(&dir)?
+                    .into_iter()
+                    .filter(|key| key.starts_with(&prefix))
+                    .collect();
+                keys.sort();
+                anyhow::Ok(keys)
+            })
+            .await?
+        })
+    }
+}
+
+fn file_len(path: &Path) -> anyhow::Result<Option<usize>> {
+    match std::fs::metadata(path) {
+        Ok(metadata) => Ok(Some(usize::try_from(metadata.len())?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The keys in a namespace's directory, skipping temporary files.
+fn read_keys(dir: &Path) -> anyhow::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut keys = vec![];
+    for entry in entries {
+        if let Some(key) = entry?.file_name().to_str().and_then(decode_file_name) {
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+fn read_usage(dir: &Path) -> anyhow::Result<KvUsage> {
+    let mut usage = KvUsage::default();
+    for key in read_keys(dir)? {
+        if let Some(len) = file_len(&dir.join(encode_file_name(&key)))? {
+            usage.add(&key, len);
+        }
+    }
+    Ok(usage)
+}
+
+/// Prefixed, so the empty key still has a file name.
+fn encode_file_name(key: &str) -> String {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut file_name = String::from("k");
+    for byte in key.bytes() {
+        file_name.push(char::from(HEX[usize::from(byte >> 4)]));
+        file_name.push(char::from(HEX[usize::from(byte & 0xf)]));
+    }
+    file_name
+}
+
+fn decode_file_name(file_name: &str) -> Option<String> {
+    let file_name = file_name.strip_prefix('k')?;
+    if file_name.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..file_name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(file_name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+#[tokio::test]
+async fn test_kv_limits() {
+    let mut state = KvState::new(KvConfig {
+        store: Arc::new(MemoryKvStore::new()),
+        namespace: "test".to_string(),
+        limits: KvLimits {
+            max_operations: ResourceLimit(3),
+            max_bytes: MemorySizeBytes(100),
+            max_value_bytes: MemorySizeBytes(10),
+            ..Default::default()
+        },
+    });
+    assert!(state.set("a", "x".repeat(11)).await.is_err());
+    state.set("a:1", "one".to_string()).await.unwrap();
+    state.set("b", "two".to_string()).await.unwrap();
+    assert_eq!(state.list("a:").await.unwrap(), vec!["a:1".to_string()]);
+    assert_eq!(
+        state.get("a:1").await.unwrap_err(),
+        "KV operation limit exceeded".to_string()
+    );
+}
+
+#[tokio::test]
+async fn test_kv_namespace_limits() {
+    let limits = KvLimits {
+        max_keys: ResourceLimit(2),
+        max_stored_bytes: MemorySizeBytes(10),
+        ..Default::default()
+    };
+    let dir = std::env::temp_dir().join(format!("kv-limits-test-{}", std::process::id()));
+    let stores: [Arc<dyn KvStore>; 2] = [
+        Arc::new(MemoryKvStore::new()),
+        Arc::new(FileKvStore::new(&dir).unwrap()),
+    ];
+    for store in stores {
+        store.set("ns", "a", "1".to_string(), limits).await.unwrap();
+        store.set("ns", "b", "2".to_string(), limits).await.unwrap();
+        let err = store.set("ns", "c", "3".to_string(), limits).await;
+        assert_eq!(err.unwrap_err().to_string(), "KV key limit exceeded");
+        // Replacing a value doesn't add a key.
+        store
+            .set("ns", "a", "1234".to_string(), limits)
+            .await
+            .unwrap();
+        let err = store.set("ns", "b", "12345".to_string(), limits).await;
+        assert_eq!(err.unwrap_err().to_string(), "KV storage limit exceeded");
+        assert!(store.delete("ns", "b").await.unwrap());
+        store.set("ns", "c", "3".to_string(), limits).await.unwrap();
+        // Each namespace has its own limits.
+        store
+            .set("other", "a", "1".to_string(), limits)
+            .await
+            .unwrap();
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_file_kv_store() {
+    let dir = std::env::temp_dir().join(format!("kv-test-{}", std::process::id()));
+    let limits = KvLimits::default();
+    let store = FileKvStore::new(&dir).unwrap();
+    store
+        .set("tenant", "cursor", "42".to_string(), limits)
+        .await
+        .unwrap();
+    store
+        .set("tenant", "a/../b", "1".to_string(), limits)
+        .await
+        .unwrap();
+    let reopened = FileKvStore::new(&dir).unwrap();
+    assert_eq!(
+        reopened.get("tenant", "cursor").await.unwrap(),
+        Some("42".to_string())
+    );
+    assert_eq!(
+        reopened.list("tenant", "").await.unwrap(),
+        vec!["a/../b".to_string(), "cursor".to_string()]
+    );
+    assert!(reopened.delete("tenant", "cursor").await.unwrap());
+    assert!(reopened.get("other", "cursor").await.unwrap().is_none());
+    assert!(reopened.get("../escape", "cursor").await.is_err());
+    std::fs::remove_dir_all(dir).unwrap();
+}
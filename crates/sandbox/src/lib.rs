@@ -1,30 +1,58 @@
 #![deny(warnings, clippy::all, clippy::pedantic, clippy::unwrap_used)]
 #![allow(clippy::missing_errors_doc, clippy::unused_async_trait_impl)]
 
+mod batch;
+mod calibration;
+mod clocks;
+mod determinism;
+mod host_functions;
 mod http;
 mod imports;
 mod ip_utils;
+mod js_error;
+mod kv;
 mod limit_values;
 mod memory;
+mod output;
+mod phases;
+mod profile;
 mod sandbox;
+mod secrets;
 mod shared_vec;
 mod state;
+mod stream;
 mod tsutils;
+mod value;
 
+pub use batch::{BatchBudget, ModuleCall, SandboxBatchResult};
+pub use calibration::FuelCalibration;
+pub use determinism::{ClockMode, Determinism};
+pub use host_functions::{HostCall, HostFunctionFuture, HostFunctionRegistry, HostFunctions};
 pub use http::{CustomHttpMode, HttpMode, OutboundRequest, RequestValidationOutcome};
 pub use hyper::{Request, Uri};
 
 pub use imports::{CustomImportMap, ImportMap, ResolvedModule, StaticImportSource};
+pub use js_error::{JavaScriptError, StackFrame};
+pub use kv::{FileKvStore, KvConfig, KvFuture, KvLimits, KvStore, MemoryKvStore};
 pub use limit_values::{
-    ApiRequestBodyLimit, CpuFuel, MemoryLimitBytes, MemorySizeBytes, RequestLimit, ResourceLimit,
-    TableLimit,
+    ApiRequestBodyLimit, CpuFuel, CpuTime, MemoryLimitBytes, MemorySizeBytes, RequestLimit,
+    ResourceLimit, SessionIdleTimeout, TableLimit, WallTimeLimit,
 };
-pub use memory::MemoryLimits;
-pub use sandbox::{EvaluateError, EvaluateMode, SandboxConfig, SandboxEngine};
+pub use memory::{InstancePoolLimits, MemoryLimits};
+pub use output::{LogLevel, LogRecord, OutputChunk, OutputKind, OutputSink};
+pub use phases::{Phase, PhaseUsage};
+pub use sandbox::{
+    EvaluateError, EvaluateMode, ParsedScript, SandboxConfig, SandboxEngine, SandboxEngineConfig,
+    SandboxEvaluationResult, SandboxSession,
+};
+pub use secrets::{Secret, Secrets};
+pub use stream::{ItemSink, StreamConfig, StreamLimits};
+pub use tokio_util::sync::CancellationToken;
 pub use tsutils::{
     ModuleExport, StaticImport, TsUtilsEngine, TsUtilsEvaluateError, TsUtilsSandboxConfig,
     TsUtilsSandboxInstance, ValidateModuleMode,
 };
+pub use value::{ResultLimit, ResultLimits, SandboxValue, ValueEncoding};
 pub use wasmtime::{StoreLimits, StoreLimitsBuilder};
 pub use wasmtime_wasi::WasiCtx;
 pub use wasmtime_wasi::p2::pipe::MemoryOutputPipe;
@@ -42,7 +70,7 @@ mod tests {
         let result = engine
             .evaluate(
                 "function (a, b) { return a + b; }",
-                &vec![json!(40), json!(2)],
+                &vec![json!(40).into(), json!(2).into()],
                 Default::default(),
             )
             .await
@@ -51,24 +79,860 @@ mod tests {
         assert_eq!(result, json!(42));
     }
 
+    #[tokio::test]
+    async fn test_pooled_engine() {
+        let engine = SandboxEngine::with_config(&SandboxEngineConfig {
+            instance_pool: Some(InstancePoolLimits::default()),
+            ..Default::default()
+        })
+        .unwrap();
+        for _ in 0..3 {
+            let result = engine
+                .evaluate(
+                    "function (a, b) { return a + b; }",
+                    &vec![json!(40).into(), json!(2).into()],
+                    Default::default(),
+                )
+                .await
+                .result
+                .unwrap();
+            assert_eq!(result, json!(42));
+        }
+    }
+
     #[tokio::test]
     async fn test_async_function() {
         let engine = SandboxEngine::new().unwrap();
         let result = engine.evaluate(
             "async function (a, b) { await new Promise(r => setTimeout(r, 100)); return a + b; }",
-            &vec![json!(40), json!(2)],
+            &vec![json!(40).into(), json!(2).into()],
             Default::default(),
         ).await.result.unwrap();
         assert_eq!(result, json!(42));
     }
 
+    #[tokio::test]
+    async fn test_wall_time_limit() {
+        let engine = SandboxEngine::new().unwrap();
+        let start = std::time::Instant::now();
+        let result = engine
+            .evaluate(
+                "async function () { await new Promise(r => setTimeout(r, 1e9)); }",
+                &vec![],
+                SandboxConfig {
+                    wall_time_limit: WallTimeLimit::Limited(100),
+                    ..Default::default()
+                },
+            )
+            .await
+            .result;
+        assert!(matches!(result, Err(EvaluateError::Timeout)));
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let engine = SandboxEngine::new().unwrap();
+        let cancel = CancellationToken::new();
+        let cancel_later = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            cancel_later.cancel();
+        });
+        let result = engine
+            .evaluate_cancellable(
+                "function () { console.log('started'); while (true) {} }",
+                &vec![],
+                SandboxConfig {
+                    cpu_fuel: CpuFuel::try_from(100_000_000_000).unwrap(),
+                    ..Default::default()
+                },
+                &cancel,
+            )
+            .await;
+        assert!(matches!(result.result, Err(EvaluateError::Cancelled)));
+        assert_eq!(result.stdout, "started\n");
+    }
+
+    #[tokio::test]
+    async fn test_fuel_yielding() {
+        let engine = SandboxEngine::new().unwrap();
+        let (evaluated_at, ticked_at) = tokio::join!(
+            async {
+                let result = engine
+                    .evaluate(
+                        "function () { while (true) {} }",
+                        &vec![],
+                        SandboxConfig::default(),
+                    )
+                    .await;
+                assert!(matches!(result.result, Err(EvaluateError::FuelExhausted)));
+                std::time::Instant::now()
+            },
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                std::time::Instant::now()
+            },
+        );
+        assert!(ticked_at < evaluated_at);
+    }
+
+    #[tokio::test]
+    async fn test_compute_threads() {
+        let engine = SandboxEngine::with_config(&SandboxEngineConfig {
+            compute_threads: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+        let result = engine
+            .evaluate(
+                "function (a, b) { return a + b; }",
+                &vec![json!(40).into(), json!(2).into()],
+                SandboxConfig::default(),
+            )
+            .await
+            .result
+            .unwrap();
+        assert_eq!(result, json!(42));
+
+        let script = engine
+            .parse(
+                "function (a, b) { return a * b; }",
+                SandboxConfig::default(),
+            )
+            .await
+            .unwrap();
+        let result = script
+            .invoke(&vec![json!(6).into(), json!(7).into()])
+            .await
+            .result
+            .unwrap();
+        assert_eq!(result, json!(42));
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_batch() {
+        let engine = SandboxEngine::new().unwrap();
+        let code = "function (a, b) { globalThis.count = (globalThis.count ?? 0) + 1; while (a === 'spin') {} return [a + b, count]; }";
+        let batch = vec![
+            vec![json!(1).into(), json!(2).into()],
+            vec![json!("spin").into(), json!(0).into()],
+            vec![json!(3).into(), json!(4).into()],
+        ];
+        let result = engine
+            .evaluate_batch(
+                code,
+                &batch,
+                SandboxConfig::default(),
+                BatchBudget::Aggregate,
+            )
+            .await;
+        assert!(result.load.result.is_ok());
+        assert_eq!(result.items.len(), 3);
+        assert_eq!(result.items[0].result.as_ref().unwrap(), &json!([3, 1]));
+        assert!(matches!(
+            result.items[1].result,
+            Err(EvaluateError::FuelExhausted)
+        ));
+        assert!(matches!(
+            result.items[2].result,
+            Err(EvaluateError::SessionTerminated)
+        ));
+
+        // Each item gets its own fuel, and a fresh sandbox after one traps.
+        let result = engine
+            .evaluate_batch(code, &batch, SandboxConfig::default(), BatchBudget::PerItem)
+            .await;
+        assert!(matches!(
+            result.items[1].result,
+            Err(EvaluateError::FuelExhausted)
+        ));
+        assert_eq!(result.items[2].result.as_ref().unwrap(), &json!([7, 1]));
+        // The fuel used to load the code again is charged to the next item.
+        assert!(result.items[2].fuel_remaining < result.items[0].fuel_remaining);
+
+        let result = engine
+            .evaluate_batch(
+                "function (a) { return a; ",
+                &batch,
+                SandboxConfig::default(),
+                BatchBudget::Aggregate,
+            )
+            .await;
+        assert!(matches!(
+            result.load.result,
+            Err(EvaluateError::JavaScriptError(_))
+        ));
+        assert!(result.items.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_module_calls() {
+        let engine = SandboxEngine::new().unwrap();
+        let code = "let seen = 0;
+            export function validate(x) { seen++; return typeof x === 'number'; }
+            export function transform(x) { console.log('transform', x); return x * 2; }
+            export function summarize() { return { seen }; }";
+        let calls = [
+            ModuleCall {
+                method: "validate".into(),
+                parameters: vec![json!(2).into()],
+            },
+            ModuleCall {
+                method: "transform".into(),
+                parameters: vec![json!(2).into()],
+            },
+            ModuleCall {
+                method: "summarize".into(),
+                parameters: vec![],
+            },
+        ];
+        let result = engine
+            .evaluate_module_calls(code, &calls, SandboxConfig::default())
+            .await;
+        assert!(result.load.result.is_ok());
+        let results: Vec<_> = result
+            .items
+            .iter()
+            .map(|item| item.result.as_ref().unwrap().clone())
+            .collect();
+        assert_eq!(results, vec![json!(true), json!(4), json!({"seen": 1})]);
+        assert_eq!(result.items[0].stdout, "");
+        assert_eq!(result.items[1].stdout, "transform 2\n");
+        assert!(result.items[1].fuel_remaining < result.items[0].fuel_remaining);
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let engine = SandboxEngine::new().unwrap();
+        let mut session = engine.open_session(SandboxConfig::default()).await.unwrap();
+        for expected in 1..=3 {
+            let result = session
+                .evaluate(
+                    "function () { globalThis.count = (globalThis.count ?? 0) + 1; console.log(count); return count; }",
+                    &vec![],
+                )
+                .await;
+            assert_eq!(result.result.unwrap(), json!(expected));
+            assert_eq!(result.stdout, format!("{expected}\n"));
+        }
+        let result = session
+            .evaluate("function () { while (true) {} }", &vec![])
+            .await;
+        assert!(matches!(result.result, Err(EvaluateError::FuelExhausted)));
+        assert!(session.is_terminated());
+        let result = session
+            .evaluate("function () { return count; }", &vec![])
+            .await;
+        assert!(matches!(
+            result.result,
+            Err(EvaluateError::SessionTerminated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_session_output_limit_per_call() {
+        let engine = SandboxEngine::new().unwrap();
+        let mut session = engine
+            .open_session(SandboxConfig {
+                memory_limits: MemoryLimits {
+                    stdout_bytes: MemorySizeBytes(10),
+                    ..MemoryLimits::default()
+                },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        let result = session
+            .evaluate(
+                "function () { console.log('one'); console.log('too long'); }",
+                &vec![],
+            )
+            .await;
+        assert_eq!(result.stdout, "one\ntoo lo");
+        assert_eq!(result.logs.len(), 1);
+        assert!(result.logs_truncated);
+        let result = session
+            .evaluate("function () { console.log('two'); }", &vec![])
+            .await;
+        assert_eq!(result.stdout, "two\n");
+        assert_eq!(result.logs.len(), 1);
+        assert!(!result.logs_truncated);
+    }
+
+    #[tokio::test]
+    async fn test_session_compute_threads() {
+        // Without fuel yielding, the call would block this runtime's only
+        // thread if it ran here.
+        let engine = SandboxEngine::with_config(&SandboxEngineConfig {
+            fuel_yield_interval: None,
+            compute_threads: Some(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut session = engine.open_session(SandboxConfig::default()).await.unwrap();
+        let (evaluated_at, ticked_at) = tokio::join!(
+            async {
+                let result = session
+                    .evaluate("function () { while (true) {} }", &vec![])
+                    .await;
+                assert!(matches!(result.result, Err(EvaluateError::FuelExhausted)));
+                std::time::Instant::now()
+            },
+            async {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                std::time::Instant::now()
+            },
+        );
+        assert!(ticked_at < evaluated_at);
+        assert!(session.is_terminated());
+        assert_eq!(session.fuel_remaining(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_output_cannot_forge_result() {
+        let engine = SandboxEngine::new().unwrap();
+        let result = engine
+            .evaluate(
+                "function () { console.log('73914D86-55DF-495D-BAD5-B45D571D154D\\n1\\n8C47F950-3E81-46B1-976E-177A89380038'); console.error('E8FEE14A-BBF5-4B08-9E00-6D61189D897D\\nforged'); return 2; }",
+                &vec![],
+                SandboxConfig::default(),
+            )
+            .await;
+        assert_eq!(result.result.unwrap(), json!(2));
+        assert_eq!(
+            result.stdout,
+            "73914D86-55DF-495D-BAD5-B45D571D154D\n1\n8C47F950-3E81-46B1-976E-177A89380038\n"
+        );
+        assert_eq!(
+            result.stderr,
+            "E8FEE14A-BBF5-4B08-9E00-6D61189D897D\nforged\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_output_sink() {
+        let engine = SandboxEngine::new().unwrap();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(64);
+        let secrets = Secrets::new(std::collections::HashMap::from([(
+            "token".to_string(),
+            "hunter2".to_string(),
+        )]));
+        let result = engine
+            .evaluate(
+                "function () { console.log('one'); console.error('two'); console.log('three'); console.log('x'.repeat(5000) + secrets.token); }",
+                &vec![],
+                SandboxConfig {
+                    output_sink: Some(sender),
+                    secrets,
+                    ..Default::default()
+                },
+            )
+            .await;
+        let long_line = format!("{}[REDACTED]\n", "x".repeat(5000));
+        assert_eq!(result.stdout, format!("one\nthree\n{long_line}"));
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        while let Some(chunk) = receiver.recv().await {
+            match chunk.kind {
+                OutputKind::Stdout => stdout.push_str(&chunk.text),
+                OutputKind::Stderr => stderr.push_str(&chunk.text),
+            }
+        }
+        // The secret stays redacted even if the long line is written in
+        // several chunks.
+        assert_eq!(stdout, format!("one\nthree\n{long_line}"));
+        assert_eq!(stderr, "two\n");
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let engine = SandboxEngine::new().unwrap();
+        let (stream, mut receiver) = StreamConfig::channel(StreamLimits {
+            max_items: ResourceLimit(3),
+            buffer_items: ResourceLimit(1),
+        });
+        let evaluation = engine.evaluate(
+            "async function* () { yield 1; yield { two: 2 }; yield new Uint8Array([3]); return 'done'; }",
+            &vec![],
+            SandboxConfig {
+                stream: Some(stream),
+                ..Default::default()
+            },
+        );
+        let items = async {
+            let mut items = vec![];
+            while let Some(item) = receiver.recv().await {
+                items.push(item);
+            }
+            items
+        };
+        let (result, items) = tokio::join!(evaluation, items);
+        assert_eq!(result.result.unwrap(), json!("done"));
+        assert_eq!(
+            items,
+            vec![
+                json!(1).into(),
+                json!({"two": 2}).into(),
+                SandboxValue::Bytes(vec![3])
+            ]
+        );
+
+        let (stream, _receiver) = StreamConfig::channel(StreamLimits {
+            max_items: ResourceLimit(2),
+            buffer_items: ResourceLimit(10),
+        });
+        let result = engine
+            .evaluate(
+                "async function* () { while (true) yield 1; }",
+                &vec![],
+                SandboxConfig {
+                    stream: Some(stream),
+                    ..Default::default()
+                },
+            )
+            .await;
+        let Err(EvaluateError::JavaScriptError(error)) = result.result else {
+            panic!("expected a JavaScript error");
+        };
+        assert_eq!(error.message, "Stream is longer than the limit of 2 items");
+    }
+
+    #[tokio::test]
+    async fn test_profile() {
+        let engine = SandboxEngine::with_config(&SandboxEngineConfig {
+            profile_interval: std::time::Duration::from_micros(500),
+            ..Default::default()
+        })
+        .unwrap();
+        // Short enough that the default 10ms epoch would only sample it once
+        let code = "function () { const end = Date.now() + 10; while (Date.now() < end); }";
+        let result = engine
+            .evaluate(code, &vec![], SandboxConfig::default())
+            .await;
+        assert!(result.profile.is_none());
+
+        let result = engine
+            .evaluate(
+                code,
+                &vec![],
+                SandboxConfig {
+                    profile: true,
+                    ..Default::default()
+                },
+            )
+            .await;
+        result.result.unwrap();
+        let profile: serde_json::Value = serde_json::from_slice(&result.profile.unwrap()).unwrap();
+        assert!(profile["meta"].is_object());
+        let thread = &profile["threads"][0];
+        assert!(thread["samples"]["length"].as_u64().unwrap() >= 5);
+        // The export that ran the code is named after its JavaScript function
+        assert!(
+            thread["stringArray"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|name| name == "evaluate")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fuel_calibration() {
+        let calibration = FuelCalibration::measure().await.unwrap();
+        // Even slow machines run far more than 1M instructions per second
+        assert!(calibration.fuel_per_second > 1_000_000);
+        let fuel = calibration.fuel_for(CpuTime(100));
+        let engine = SandboxEngine::new().unwrap();
+        let result = engine
+            .evaluate(
+                "function () { while (true); }",
+                &vec![],
+                SandboxConfig {
+                    cpu_fuel: fuel,
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(matches!(result.result, Err(EvaluateError::FuelExhausted)));
+        // The code differs from the calibration's, and test machines are
+        // noisy, so only check that the time is roughly right.
+        let execute = result
+            .phases
+            .iter()
+            .find(|usage| usage.phase == Phase::Execute)
+            .unwrap();
+        assert!(
+            (25..=400).contains(&execute.duration.as_millis()),
+            "100ms of fuel took {:?}",
+            execute.duration
+        );
+    }
+
+    #[tokio::test]
+    async fn test_logs() {
+        let engine = SandboxEngine::new().unwrap();
+        let result = engine
+            .evaluate(
+                "function () {\n  console.log('one', { a: 1 });\n  console.warn('two');\n}",
+                &vec![],
+                Default::default(),
+            )
+            .await;
+        let logs: Vec<_> = result
+            .logs
+            .iter()
+            .map(|log| (log.level, log.message.as_str(), log.location.clone()))
+            .collect();
+        assert_eq!(
+            logs,
+            vec![
+                (
+                    LogLevel::Info,
+                    "one {\"a\":1}",
+                    Some(StackFrame {
+                        function: None,
+                        filename: "input.js".to_string(),
+                        line: 2,
+                        column: 11,
+                        async_boundary: false,
+                    })
+                ),
+                (
+                    LogLevel::Warn,
+                    "two",
+                    Some(StackFrame {
+                        function: None,
+                        filename: "input.js".to_string(),
+                        line: 3,
+                        column: 11,
+                        async_boundary: false,
+                    })
+                ),
+            ]
+        );
+        assert!(!result.logs_truncated);
+    }
+
+    #[tokio::test]
+    async fn test_determinism() {
+        let engine = SandboxEngine::new().unwrap();
+        let code = "function () { return [Date.now(), Math.random(), crypto.getRandomValues(new Uint8Array(4))]; }";
+        let config = SandboxConfig {
+            determinism: Some(Determinism {
+                seed: Some(42),
+                start_time_ms: 1_000,
+                clock: ClockMode::Fixed,
+            }),
+            ..Default::default()
+        };
+        let first = engine.evaluate(code, &vec![], config.clone()).await;
+        let second = engine.evaluate(code, &vec![], config).await;
+        assert_eq!(first.seed, Some(42));
+        let first = first.result.unwrap();
+        assert_eq!(first.as_json().unwrap()[0], json!(1_000));
+        assert_eq!(first, second.result.unwrap());
+
+        let real_clock = engine
+            .evaluate(
+                code,
+                &vec![],
+                SandboxConfig {
+                    determinism: Some(Determinism {
+                        seed: Some(42),
+                        start_time_ms: 1_000,
+                        clock: ClockMode::Real,
+                    }),
+                    ..Default::default()
+                },
+            )
+            .await
+            .result
+            .unwrap();
+        let real_clock = real_clock.as_json().unwrap();
+        assert!(real_clock[0].as_f64().unwrap() > 1e12);
+        assert_eq!(real_clock[1], first.as_json().unwrap()[1]);
+
+        let random_seed = engine
+            .evaluate(
+                code,
+                &vec![],
+                SandboxConfig {
+                    determinism: Some(Determinism::default()),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(random_seed.seed.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_virtual_time() {
+        let engine = SandboxEngine::new().unwrap();
+        let start = std::time::Instant::now();
+        let result = engine
+            .evaluate(
+                "async function () {
+                    const start = Date.now();
+                    await new Promise(resolve => setTimeout(resolve, 60_000));
+                    return Date.now() - start;
+                }",
+                &vec![],
+                SandboxConfig {
+                    virtual_time: true,
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(result.result.unwrap(), json!(60_000));
+        assert_eq!(
+            result.virtual_time_elapsed,
+            Some(std::time::Duration::from_secs(60))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_functions() {
+        let mut host_functions = HostFunctionRegistry::new();
+        host_functions.register("add", |args: Vec<serde_json::Value>| async move {
+            let sum: i64 = args.iter().filter_map(serde_json::Value::as_i64).sum();
+            Ok(json!(sum))
+        });
+        let engine = SandboxEngine::with_config(&SandboxEngineConfig {
+            host_functions: Some(std::sync::Arc::new(host_functions)),
+            ..Default::default()
+        })
+        .unwrap();
+        let result = engine
+            .evaluate(
+                "async function () {
+                    const sum = await host.add(1, 2);
+                    try {
+                        await host.missing();
+                    } catch (e) {
+                        return [sum, e.message];
+                    }
+                }",
+                &vec![],
+                Default::default(),
+            )
+            .await;
+        assert_eq!(
+            result.result.unwrap(),
+            json!([3, "Unknown host function: missing"])
+        );
+        let calls: Vec<_> = result
+            .host_calls
+            .iter()
+            .map(|call| (call.name.as_str(), call.success))
+            .collect();
+        assert_eq!(calls, vec![("add", true), ("missing", false)]);
+
+        let result = engine
+            .evaluate(
+                "import { add } from 'sandbox:host';
+                export async function run() { return await add(2, 3); }",
+                &vec![],
+                SandboxConfig {
+                    mode: EvaluateMode::ModuleMethod("run".into()),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert_eq!(result.result.unwrap(), json!(5));
+    }
+
+    #[tokio::test]
+    async fn test_kv() {
+        let engine = SandboxEngine::new().unwrap();
+        let config = SandboxConfig {
+            kv: Some(KvConfig {
+                store: std::sync::Arc::new(MemoryKvStore::new()),
+                namespace: "tenant".to_string(),
+                limits: KvLimits::default(),
+            }),
+            ..Default::default()
+        };
+        let result = engine
+            .evaluate(
+                "async function () {
+                    await kv.set('cursor', { page: 1 });
+                    await kv.set('other', 'value');
+                    return [await kv.get('cursor'), await kv.get('missing'), await kv.list('cur')];
+                }",
+                &vec![],
+                config.clone(),
+            )
+            .await;
+        assert_eq!(
+            result.result.unwrap(),
+            json!([{ "page": 1 }, null, ["cursor"]])
+        );
+        let result = engine
+            .evaluate(
+                "async function () { return [await kv.delete('cursor'), await kv.list()]; }",
+                &vec![],
+                config,
+            )
+            .await;
+        assert_eq!(result.result.unwrap(), json!([true, ["other"]]));
+
+        let result = engine
+            .evaluate(
+                "async function () { await kv.get('cursor'); }",
+                &vec![],
+                Default::default(),
+            )
+            .await;
+        assert!(
+            result
+                .result
+                .unwrap_err()
+                .to_string()
+                .contains("KV storage is not enabled")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_secrets() {
+        let engine = SandboxEngine::new().unwrap();
+        let config = SandboxConfig {
+            secrets: Secrets::new(std::collections::HashMap::from([(
+                "token".to_string(),
+                "hunter2".to_string(),
+            )])),
+            ..Default::default()
+        };
+        let result = engine
+            .evaluate(
+                "function () {
+                    console.log('token is', secrets.token);
+                    return [secrets.token.length, 'token' in secrets, 'missing' in secrets];
+                }",
+                &vec![],
+                config.clone(),
+            )
+            .await;
+        assert_eq!(result.result.unwrap(), json!([7, true, false]));
+        assert_eq!(result.stdout, "token is [REDACTED]\n");
+        assert_eq!(result.logs[0].message, "token is [REDACTED]");
+
+        let result = engine
+            .evaluate(
+                "function () { throw new Error(`bad token ${secrets.token}`); }",
+                &vec![],
+                config,
+            )
+            .await;
+        let Err(EvaluateError::JavaScriptError(error)) = result.result else {
+            panic!("expected a JavaScript error");
+        };
+        assert_eq!(error.message, "bad token [REDACTED]");
+    }
+
+    #[tokio::test]
+    async fn test_binary_values() {
+        let engine = SandboxEngine::new().unwrap();
+        let result = engine
+            .evaluate(
+                "function (bytes, offset) {
+                    return bytes instanceof Uint8Array ? bytes.map(b => b + offset) : null;
+                }",
+                &vec![vec![1, 2, 3].into(), json!(10).into()],
+                Default::default(),
+            )
+            .await;
+        assert_eq!(
+            result.result.unwrap(),
+            SandboxValue::Bytes(vec![11, 12, 13])
+        );
+
+        let result = engine
+            .evaluate(
+                "function () { return new TextEncoder().encode('hi').buffer; }",
+                &vec![],
+                Default::default(),
+            )
+            .await;
+        assert_eq!(result.result.unwrap().as_bytes(), Some(&b"hi"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_tagged_values() {
+        let engine = SandboxEngine::new().unwrap();
+        let config = SandboxConfig {
+            value_encoding: ValueEncoding::Tagged,
+            ..Default::default()
+        };
+        let result = engine
+            .evaluate(
+                "function (big, map) {
+                    return [undefined, NaN, -0, big * 2n, new Date(0), map.get('a'), new Set([1]), { $type: 'x' }];
+                }",
+                &vec![
+                    json!({ "$type": "bigint", "value": "9007199254740993" }).into(),
+                    json!({ "$type": "map", "entries": [["a", 1]] }).into(),
+                ],
+                config,
+            )
+            .await;
+        assert_eq!(
+            result.result.unwrap(),
+            json!([
+                { "$type": "undefined" },
+                { "$type": "number", "value": "NaN" },
+                { "$type": "number", "value": "-0" },
+                { "$type": "bigint", "value": "18014398509481986" },
+                { "$type": "date", "value": "1970-01-01T00:00:00.000Z" },
+                1,
+                { "$type": "set", "values": [1] },
+                { "$type": "object", "value": { "$type": "x" } },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_result_limit_exceeded() {
+        let engine = SandboxEngine::new().unwrap();
+        let config = SandboxConfig {
+            result_limits: ResultLimits {
+                max_bytes: MemorySizeBytes(1000),
+                max_depth: ResourceLimit(10),
+            },
+            ..Default::default()
+        };
+        let result = engine
+            .evaluate(
+                "function () { return 'x'.repeat(2000); }",
+                &vec![],
+                config.clone(),
+            )
+            .await;
+        assert!(matches!(
+            result.result,
+            Err(EvaluateError::ResultLimitExceeded(ResultLimit::Bytes(1000)))
+        ));
+        let result = engine
+            .evaluate(
+                "function () { let value = []; for (let i = 0; i < 20; i++) value = [value]; return value; }",
+                &vec![],
+                config,
+            )
+            .await;
+        assert!(matches!(
+            result.result,
+            Err(EvaluateError::ResultLimitExceeded(ResultLimit::Depth(10)))
+        ));
+    }
+
     #[tokio::test]
     async fn test_typescript_function() {
         let engine = SandboxEngine::new().unwrap();
         let result = engine
             .evaluate(
                 "function (a: number, b: number): number { return a + b; }",
-                &vec![json!(40), json!(2)],
+                &vec![json!(40).into(), json!(2).into()],
                 SandboxConfig {
                     strip_typescript_types: true,
                     ..Default::default()
@@ -86,7 +950,7 @@ mod tests {
         let result = engine
             .evaluate(
                 "export function run(a, b) { return a + b; }",
-                &vec![json!(40), json!(2)],
+                &vec![json!(40).into(), json!(2).into()],
                 SandboxConfig {
                     mode: EvaluateMode::ModuleMethod("run".into()),
                     ..Default::default()
@@ -104,7 +968,7 @@ mod tests {
         let result = engine
             .evaluate(
                 "export function run(a: number, b: number): number { return a + b; }",
-                &vec![json!(40), json!(2)],
+                &vec![json!(40).into(), json!(2).into()],
                 SandboxConfig {
                     mode: EvaluateMode::ModuleMethod("run".into()),
                     strip_typescript_types: true,
@@ -131,13 +995,17 @@ mod tests {
         let engine = SandboxEngine::new().unwrap();
         let code = "import * as ft from 'https://unpkg.com/funtypes@5.1.2/lib/index.mjs'; export function run(input: string): number { const result = ft.Array(ft.String).safeParse(JSON.parse(input)); return result.success ? result : { success: false, reason: ft.showError(result) };}";
         let result = engine
-            .evaluate(code, &vec![json!("[\"a\", \"b\", \"c\"]")], config.clone())
+            .evaluate(
+                code,
+                &vec![json!("[\"a\", \"b\", \"c\"]").into()],
+                config.clone(),
+            )
             .await
             .result
             .unwrap();
         assert_eq!(result, json!({ "success": true, "value": ["a", "b", "c"] }));
         let result = engine
-            .evaluate(code, &vec![json!("[\"a\", 42, \"c\"]")], config)
+            .evaluate(code, &vec![json!("[\"a\", 42, \"c\"]").into()], config)
             .await
             .result
             .unwrap();
@@ -147,6 +1015,30 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_parsed_script() {
+        let engine = SandboxEngine::new().unwrap();
+        let script = engine
+            .parse(
+                "export function run(a: number, b: number): number { return a + b; }",
+                SandboxConfig {
+                    mode: EvaluateMode::ModuleMethod("run".into()),
+                    strip_typescript_types: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        for i in 0..3 {
+            let result = script
+                .invoke(&vec![json!(40).into(), json!(i).into()])
+                .await
+                .result
+                .unwrap();
+            assert_eq!(result, json!(40 + i));
+        }
+    }
+
     // Fetching from data URIs is currently not supported.
     // #[tokio::test]
     // async fn test_fetch_data_uri() {
@@ -1,5 +1,5 @@
 use serde::{Deserialize, de::Visitor};
-use std::{marker::PhantomData, str::FromStr};
+use std::{marker::PhantomData, str::FromStr, time::Duration};
 
 #[derive(Clone, Copy)]
 enum ScalePrefix {
@@ -83,6 +83,20 @@ impl SuffixParser for MemorySuffix {
     }
 }
 
+/// Parses durations into milliseconds, e.g. "500ms", "2s", "1m" or "1h"
+struct DurationSuffix;
+impl SuffixParser for DurationSuffix {
+    fn parse(a: char, b: Option<char>) -> Option<u64> {
+        match (a, b) {
+            ('m', Some('s')) => Some(1),
+            ('s', None) => Some(1000),
+            ('m', None) => Some(60 * 1000),
+            ('h', None) => Some(60 * 60 * 1000),
+            _ => None,
+        }
+    }
+}
+
 struct NoUnitSuffix;
 impl SuffixParser for NoUnitSuffix {
     fn parse(a: char, b: Option<char>) -> Option<u64> {
@@ -290,6 +304,7 @@ fn test_parse_number_without_suffix<TSuffix: SuffixParser>() {
 fn test_parse_number() {
     test_parse_number_without_suffix::<NoUnitSuffix>();
     test_parse_number_without_suffix::<MemorySuffix>();
+    test_parse_number_without_suffix::<DurationSuffix>();
 
     assert_parse_ok::<MemorySuffix>("0B", 0);
     assert_parse_ok::<NoUnitSuffix>("0M", 0);
@@ -307,6 +322,19 @@ fn test_parse_number() {
     assert_parse_ok::<NoUnitSuffix>("2.5 G", 2_500_000_000);
     assert_parse_ok::<MemorySuffix>("2.5 TB", 2_748_779_069_440);
     assert_parse_ok::<NoUnitSuffix>("2.5 T", 2_500_000_000_000);
+    assert_parse_ok::<DurationSuffix>("500ms", 500);
+    assert_parse_ok::<DurationSuffix>("2s", 2_000);
+    assert_parse_ok::<DurationSuffix>("1.5 s", 1_500);
+    assert_parse_ok::<DurationSuffix>("1m", 60_000);
+    assert_parse_ok::<DurationSuffix>("1h", 3_600_000);
+    assert!(
+        parse_u64::<DurationSuffix>("1.5ms").is_none(),
+        "expected error parsing duration with fractional milliseconds"
+    );
+    assert!(
+        parse_u64::<DurationSuffix>("1S").is_none(),
+        "expected error parsing duration with upper case suffix"
+    );
     assert!(
         parse_u64::<MemorySuffix>("1 K").is_none(),
         "expected error parsing string with incomplete suffix"
@@ -376,7 +404,13 @@ macro_rules! number_type {
 }
 
 macro_rules! optional_bound {
+    ($id:ident, $underlying:ty, $suffix:ident, name=$name:expr, expect=$expected:expr, default=UNBOUNDED, min=$min_value:expr) => {
+        optional_bound!(@impl $id, $underlying, $suffix, name=$name, expect=$expected, default=Self::Unbounded, min=$min_value);
+    };
     ($id:ident, $underlying:ty, $suffix:ident, name=$name:expr, expect=$expected:expr, default=$default_value:expr, min=$min_value:expr) => {
+        optional_bound!(@impl $id, $underlying, $suffix, name=$name, expect=$expected, default=Self::Limited($default_value), min=$min_value);
+    };
+    (@impl $id:ident, $underlying:ty, $suffix:ident, name=$name:expr, expect=$expected:expr, default=$default:expr, min=$min_value:expr) => {
         #[derive(Clone, Copy, Debug, PartialEq, Eq)]
         pub enum $id {
             Limited($underlying),
@@ -392,7 +426,7 @@ macro_rules! optional_bound {
         }
         impl Default for $id {
             fn default() -> Self {
-                Self::Limited($default_value)
+                $default
             }
         }
         impl TryFrom<$underlying> for $id {
@@ -477,6 +511,22 @@ number_type!(
     min = 1
 );
 
+number_type!(
+    CpuTime,
+    u64,
+    DurationSuffix,
+    name = "CPU Time",
+    expect = "a positive integer number of milliseconds or a string like '100ms' or '1s'",
+    default = 100,
+    min = 1
+);
+impl CpuTime {
+    #[must_use]
+    pub fn as_duration(&self) -> Duration {
+        Duration::from_millis(self.0)
+    }
+}
+
 optional_bound!(
     ApiRequestBodyLimit,
     usize,
@@ -515,6 +565,63 @@ optional_bound!(
     min = 0
 );
 
+optional_bound!(
+    WallTimeLimit,
+    u64,
+    DurationSuffix,
+    name = "Wall Time Limit",
+    expect = "a positive integer number of milliseconds or the string 'UNBOUNDED' or a string like '500ms' or '2s'",
+    default = UNBOUNDED,
+    min = 1
+);
+impl WallTimeLimit {
+    #[must_use]
+    pub fn as_duration(&self) -> Option<Duration> {
+        match self {
+            Self::Limited(millis) => Some(Duration::from_millis(*millis)),
+            Self::Unbounded => None,
+        }
+    }
+}
+
+optional_bound!(
+    SessionIdleTimeout,
+    u64,
+    DurationSuffix,
+    name = "Session Idle Timeout",
+    expect = "a positive integer number of milliseconds or the string 'UNBOUNDED' or a string like '30s' or '5m'",
+    default = 5 * 60 * 1000,
+    min = 1
+);
+impl SessionIdleTimeout {
+    #[must_use]
+    pub fn as_duration(&self) -> Option<Duration> {
+        match self {
+            Self::Limited(millis) => Some(Duration::from_millis(*millis)),
+            Self::Unbounded => None,
+        }
+    }
+}
+
+#[test]
+fn test_wall_time_limit_deserialize() {
+    let limited: WallTimeLimit =
+        serde_json::from_str("\"500ms\"").expect("failed to deserialize wall time limit");
+    assert_eq!(limited, WallTimeLimit::Limited(500));
+    let limited: WallTimeLimit =
+        serde_json::from_str("2000").expect("failed to deserialize wall time limit");
+    assert_eq!(limited, WallTimeLimit::Limited(2000));
+    assert_eq!(
+        "2s".parse::<WallTimeLimit>().unwrap().as_duration(),
+        Some(Duration::from_secs(2))
+    );
+    assert_eq!(
+        "UNBOUNDED".parse::<WallTimeLimit>().unwrap().as_duration(),
+        None
+    );
+    assert_eq!(WallTimeLimit::default(), WallTimeLimit::Unbounded);
+}
+
 #[test]
 fn test_memory_size_bytes_deserialize() {
     let limited: MemoryLimitBytes =
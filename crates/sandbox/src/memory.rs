@@ -16,6 +16,61 @@ pub struct MemoryLimits {
     pub memories: ResourceLimit,
     #[serde(default)]
     pub trap_on_grow_failure: bool,
+    /// The output kept for each call, including each call in a session.
     pub stdout_bytes: MemorySizeBytes,
     pub stderr_bytes: MemorySizeBytes,
 }
+
+/// Sizes for wasmtime's pooling instance allocator. All slots are reserved
+/// when the engine is created, and each sandbox takes one component instance
+/// slot plus the core instances, memories and tables it needs.
+#[derive(Clone, Debug, Deserialize)]
+pub struct InstancePoolLimits {
+    /// The maximum number of sandboxes that can be running at the same time.
+    #[serde(default = "default_max_sandboxes")]
+    pub max_sandboxes: ResourceLimit,
+    #[serde(default = "default_core_instances_per_sandbox")]
+    pub core_instances_per_sandbox: ResourceLimit,
+    #[serde(default = "default_memories_per_sandbox")]
+    pub memories_per_sandbox: ResourceLimit,
+    #[serde(default = "default_tables_per_sandbox")]
+    pub tables_per_sandbox: ResourceLimit,
+    /// The size of each memory slot. This should be at least as large as
+    /// `MemoryLimits::memory_size_bytes`, otherwise memory growth will fail
+    /// before that limit is reached.
+    #[serde(default = "default_max_memory_size_bytes")]
+    pub max_memory_size_bytes: MemorySizeBytes,
+    #[serde(default = "default_pool_table_elements")]
+    pub table_elements: ResourceLimit,
+}
+impl Default for InstancePoolLimits {
+    fn default() -> Self {
+        Self {
+            max_sandboxes: default_max_sandboxes(),
+            core_instances_per_sandbox: default_core_instances_per_sandbox(),
+            memories_per_sandbox: default_memories_per_sandbox(),
+            tables_per_sandbox: default_tables_per_sandbox(),
+            max_memory_size_bytes: default_max_memory_size_bytes(),
+            table_elements: default_pool_table_elements(),
+        }
+    }
+}
+
+fn default_max_sandboxes() -> ResourceLimit {
+    ResourceLimit(100)
+}
+fn default_core_instances_per_sandbox() -> ResourceLimit {
+    ResourceLimit(50)
+}
+fn default_memories_per_sandbox() -> ResourceLimit {
+    ResourceLimit(4)
+}
+fn default_tables_per_sandbox() -> ResourceLimit {
+    ResourceLimit(4)
+}
+fn default_max_memory_size_bytes() -> MemorySizeBytes {
+    MemorySizeBytes(128 * 1024 * 1024)
+}
+fn default_pool_table_elements() -> ResourceLimit {
+    ResourceLimit(100_000)
+}
@@ -0,0 +1,305 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+use tokio::sync::mpsc::Sender;
+use wasmtime_wasi::cli::{IsTerminal, StdoutStream};
+use wasmtime_wasi::p2::{OutputStream, Pollable, StreamError, StreamResult};
+
+use crate::js_error::{StackFrame, parse_stack};
+use crate::secrets::Secrets;
+
+/// Receives output from the sandbox as soon as it is written. Each chunk is
+/// usually a whole line. Chunks are dropped while the channel is full, rather
+/// than pausing the sandbox, so the `SandboxEvaluationResult` is the only
+/// complete copy of the output.
+pub type OutputSink = Sender<OutputChunk>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputKind {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Debug)]
+pub struct OutputChunk {
+    pub kind: OutputKind,
+    pub text: String,
+}
+
+/// Collects the stdout or stderr of a call, up to `capacity` bytes. The
+/// output is taken at the end of each call, so in a session each call can
+/// write up to the capacity. If there's an `OutputSink`, the output is also
+/// sent to it with secrets redacted.
+///
+/// A secret can be split across writes, so the end of the output is held
+/// back until the next write shows it isn't part of a secret, or until
+/// `flush_held_back` is called at the end of the call.
+#[derive(Clone)]
+pub(crate) struct TeeOutputPipe {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    capacity: usize,
+    kind: OutputKind,
+    sink: Option<OutputSink>,
+    secrets: Secrets,
+    held_back: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TeeOutputPipe {
+    pub fn new(
+        capacity: usize,
+        kind: OutputKind,
+        sink: Option<OutputSink>,
+        secrets: Secrets,
+    ) -> Self {
+        Self {
+            buffer: Arc::default(),
+            capacity,
+            kind,
+            sink,
+            secrets,
+            held_back: Arc::default(),
+        }
+    }
+    /// Writes as much of `bytes` as fits, and returns how much that was.
+    pub fn write_bytes(&self, bytes: &[u8]) -> usize {
+        let mut buffer = self.buffer.lock().unwrap_or_else(PoisonError::into_inner);
+        let written = bytes.len().min(self.capacity.saturating_sub(buffer.len()));
+        buffer.extend_from_slice(&bytes[..written]);
+        drop(buffer);
+        if self.sink.is_some() {
+            self.send(&bytes[..written]);
+        }
+        written
+    }
+    fn room(&self) -> usize {
+        let buffer = self.buffer.lock().unwrap_or_else(PoisonError::into_inner);
+        self.capacity.saturating_sub(buffer.len())
+    }
+    /// Whether this call's output has filled the pipe.
+    pub fn is_full(&self) -> bool {
+        self.room() == 0
+    }
+    /// Returns the output written since the last call to this.
+    pub fn take_contents(&self) -> String {
+        let contents =
+            std::mem::take(&mut *self.buffer.lock().unwrap_or_else(PoisonError::into_inner));
+        String::from_utf8(contents).unwrap_or_else(|_| "<invalid utf8 output>".to_string())
+    }
+    fn send(&self, bytes: &[u8]) {
+        let mut held_back = self
+            .held_back
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        held_back.extend_from_slice(bytes);
+        let len = self.secrets.redactable_prefix_len(&held_back);
+        // Don't split a character that the next write completes.
+        let len = match std::str::from_utf8(&held_back[..len]) {
+            Err(err) if err.error_len().is_none() => err.valid_up_to(),
+            _ => len,
+        };
+        if len > 0 {
+            let bytes: Vec<u8> = held_back.drain(..len).collect();
+            self.send_now(&bytes);
+        }
+    }
+    /// Send any output that was held back, once the call has finished.
+    pub fn flush_held_back(&self) {
+        let bytes = std::mem::take(
+            &mut *self
+                .held_back
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        if !bytes.is_empty() {
+            self.send_now(&bytes);
+        }
+    }
+    fn send_now(&self, bytes: &[u8]) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+        // The chunk is dropped if the channel is full, or if nobody is
+        // listening any more.
+        let _ = sink.try_send(OutputChunk {
+            kind: self.kind,
+            text: self.secrets.redact(&String::from_utf8_lossy(bytes)),
+        });
+    }
+}
+
+impl IsTerminal for TeeOutputPipe {
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+impl StdoutStream for TeeOutputPipe {
+    fn p2_stream(&self) -> Box<dyn OutputStream> {
+        Box::new(self.clone())
+    }
+    fn async_stream(&self) -> Box<dyn AsyncWrite + Send + Sync> {
+        Box::new(self.clone())
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Pollable for TeeOutputPipe {
+    async fn ready(&mut self) {}
+}
+
+/// Like wasmtime-wasi's `MemoryOutputPipe`, a write that doesn't fit is a
+/// trap, and the stream is closed once it's full.
+impl OutputStream for TeeOutputPipe {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        if bytes.len() > self.room() {
+            return Err(StreamError::Trap(wasmtime::format_err!(
+                "write beyond capacity of output pipe"
+            )));
+        }
+        self.write_bytes(&bytes);
+        Ok(())
+    }
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+    fn check_write(&mut self) -> StreamResult<usize> {
+        match self.room() {
+            0 => Err(StreamError::Closed),
+            room => Ok(room),
+        }
+    }
+}
+
+impl AsyncWrite for TeeOutputPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(self.write_bytes(buf)))
+    }
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// The stream that messages at this level are written to.
+    fn output_kind(self) -> OutputKind {
+        match self {
+            LogLevel::Debug | LogLevel::Info => OutputKind::Stdout,
+            LogLevel::Warn | LogLevel::Error => OutputKind::Stderr,
+        }
+    }
+}
+
+/// A message written using `console.log` and friends.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    pub message: String,
+    /// Milliseconds since the start of the call.
+    pub timestamp_ms: f64,
+    /// Where the console method was called from, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<StackFrame>,
+}
+
+/// Writes the messages from `console` methods to stdout or stderr, and
+/// collects the log records for a call. Writing them here ties each record to
+/// its own output, so a record is dropped only if its line didn't fit.
+pub(crate) struct LogRecorder {
+    start: Instant,
+    records: Vec<LogRecord>,
+    /// Whether a record was dropped during this call.
+    truncated: bool,
+    stdout: TeeOutputPipe,
+    stderr: TeeOutputPipe,
+}
+
+impl LogRecorder {
+    pub fn new(stdout: TeeOutputPipe, stderr: TeeOutputPipe) -> Self {
+        Self {
+            start: Instant::now(),
+            records: vec![],
+            truncated: false,
+            stdout,
+            stderr,
+        }
+    }
+    pub fn start_call(&mut self) {
+        self.start = Instant::now();
+        self.records.clear();
+        self.truncated = false;
+    }
+    pub fn push(&mut self, level: LogLevel, message: String, stack: Option<&str>) {
+        let pipe = match level.output_kind() {
+            OutputKind::Stdout => &self.stdout,
+            OutputKind::Stderr => &self.stderr,
+        };
+        let line = format!("{message}\n");
+        if pipe.write_bytes(line.as_bytes()) < line.len() {
+            self.truncated = true;
+            return;
+        }
+        self.records.push(LogRecord {
+            level,
+            message,
+            timestamp_ms: self.start.elapsed().as_secs_f64() * 1000.0,
+            location: stack.and_then(|stack| parse_stack(stack).into_iter().next()),
+        });
+    }
+    /// Returns the records for this call, and whether any were dropped. Output
+    /// that filled either pipe during this call also counts as truncated, so
+    /// this must be called before the pipes' contents are taken.
+    pub fn take(&mut self) -> (Vec<LogRecord>, bool) {
+        let truncated = self.truncated || self.stdout.is_full() || self.stderr.is_full();
+        self.truncated = false;
+        (std::mem::take(&mut self.records), truncated)
+    }
+}
+
+#[test]
+fn test_log_recorder_truncates() {
+    let stdout = TeeOutputPipe::new(8, OutputKind::Stdout, None, Secrets::default());
+    let stderr = TeeOutputPipe::new(100, OutputKind::Stderr, None, Secrets::default());
+    let mut recorder = LogRecorder::new(stdout.clone(), stderr.clone());
+    recorder.push(LogLevel::Info, "one".to_string(), None);
+    // Output that isn't from a console method isn't charged to a record.
+    stdout.write_bytes(b"x");
+    recorder.push(LogLevel::Error, "two".to_string(), None);
+    recorder.push(LogLevel::Debug, "three".to_string(), None);
+    recorder.push(LogLevel::Info, "4".to_string(), None);
+    let (records, truncated) = recorder.take();
+    let messages: Vec<_> = records.iter().map(|record| &record.message[..]).collect();
+    assert_eq!(messages, vec!["one", "two"]);
+    assert!(truncated);
+    assert_eq!(stdout.take_contents(), "one\nxthr");
+    assert_eq!(stderr.take_contents(), "two\n");
+
+    // The next call in a session has the whole capacity again.
+    recorder.start_call();
+    recorder.push(LogLevel::Info, "five".to_string(), None);
+    let (records, truncated) = recorder.take();
+    assert_eq!(records.len(), 1);
+    assert!(!truncated);
+    assert_eq!(stdout.take_contents(), "five\n");
+}
@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// A part of an evaluation that fuel and time are charged to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Phase {
+    /// Creating the sandbox, before any code is run.
+    Instantiate,
+    /// Stripping TypeScript types from the code, and finding a module's
+    /// imports.
+    Compile,
+    /// Loading, compiling and evaluating the top level of an imported
+    /// module, up to its first `await`. Modules it imports in turn are
+    /// charged separately. Fetching it from a URL is recorded in
+    /// `PhaseUsage::fetch_duration`, while the fuel and time are charged to
+    /// whatever runs while it waits.
+    LoadModule,
+    /// Running the code itself, including everything that isn't charged to
+    /// another phase.
+    Execute,
+}
+
+/// The fuel and time used by one phase of an evaluation. A phase that is
+/// entered more than once, e.g. `Execute` while imports are loaded, has a
+/// single entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhaseUsage {
+    pub phase: Phase,
+    /// The module's ID, for `Phase::LoadModule`.
+    pub module: Option<String>,
+    pub fuel_consumed: u64,
+    /// Wall-clock time, including time spent waiting for timers and
+    /// outbound requests.
+    pub duration: Duration,
+    /// For `Phase::LoadModule`, the wall-clock time spent fetching the
+    /// module from a URL, if it was fetched. This overlaps with other
+    /// phases rather than being part of `duration`.
+    pub fetch_duration: Option<Duration>,
+}
+
+/// Charges the fuel and time used since the last change of phase to the
+/// phase that was current.
+pub(crate) struct PhaseRecorder {
+    usage: Vec<PhaseUsage>,
+    current: (Phase, Option<String>),
+    fuel_mark: u64,
+    time_mark: Instant,
+    /// When each module being fetched from a URL started.
+    fetches: HashMap<String, Instant>,
+}
+
+impl PhaseRecorder {
+    /// Starts in `Phase::Instantiate`, until the instance is ready.
+    pub fn new(fuel: u64) -> Self {
+        Self {
+            usage: vec![],
+            current: (Phase::Instantiate, None),
+            fuel_mark: fuel,
+            time_mark: Instant::now(),
+            fetches: HashMap::new(),
+        }
+    }
+    /// Start a call, which is in `Phase::Execute` until the guest says
+    /// otherwise.
+    pub fn start(&mut self, fuel: u64) {
+        self.current = (Phase::Execute, None);
+        self.fuel_mark = fuel;
+        self.time_mark = Instant::now();
+    }
+    pub fn enter(&mut self, phase: Phase, module: Option<String>, fuel: u64) {
+        self.charge(fuel);
+        self.current = (phase, module);
+    }
+    pub fn start_fetch(&mut self, module: String) {
+        self.fetches.insert(module, Instant::now());
+    }
+    pub fn finish_fetch(&mut self, module: String) {
+        let Some(start) = self.fetches.remove(&module) else {
+            return;
+        };
+        let duration = start.elapsed();
+        let usage = self.usage_mut(Phase::LoadModule, Some(module));
+        *usage.fetch_duration.get_or_insert_default() += duration;
+    }
+    /// The usage of each phase since the last call to `finish`.
+    pub fn finish(&mut self, fuel: u64) -> Vec<PhaseUsage> {
+        self.charge(fuel);
+        std::mem::take(&mut self.usage)
+    }
+    fn charge(&mut self, fuel: u64) {
+        let now = Instant::now();
+        let fuel_consumed = self.fuel_mark.saturating_sub(fuel);
+        let duration = now.saturating_duration_since(self.time_mark);
+        self.fuel_mark = fuel;
+        self.time_mark = now;
+        let (phase, module) = self.current.clone();
+        let usage = self.usage_mut(phase, module);
+        usage.fuel_consumed += fuel_consumed;
+        usage.duration += duration;
+    }
+    fn usage_mut(&mut self, phase: Phase, module: Option<String>) -> &mut PhaseUsage {
+        let index = match self
+            .usage
+            .iter()
+            .position(|usage| usage.phase == phase && usage.module == module)
+        {
+            Some(index) => index,
+            None => {
+                self.usage.push(PhaseUsage {
+                    phase,
+                    module,
+                    fuel_consumed: 0,
+                    duration: Duration::ZERO,
+                    fetch_duration: None,
+                });
+                self.usage.len() - 1
+            }
+        };
+        &mut self.usage[index]
+    }
+}
+
+#[test]
+fn test_phase_recorder() {
+    let mut recorder = PhaseRecorder::new(100);
+    recorder.enter(Phase::Execute, None, 90);
+    recorder.enter(Phase::Compile, None, 80);
+    recorder.enter(Phase::LoadModule, Some("a".to_string()), 75);
+    recorder.enter(Phase::Execute, None, 60);
+    let usage = recorder.finish(50);
+    let fuel: Vec<_> = usage
+        .iter()
+        .map(|usage| (usage.phase, usage.module.as_deref(), usage.fuel_consumed))
+        .collect();
+    assert_eq!(
+        fuel,
+        vec![
+            (Phase::Instantiate, None, 10),
+            (Phase::Execute, None, 20),
+            (Phase::Compile, None, 5),
+            (Phase::LoadModule, Some("a"), 15),
+        ]
+    );
+
+    recorder.start(40);
+    assert_eq!(recorder.finish(30)[0].fuel_consumed, 10);
+
+    // A fetch is recorded against its module, but its fuel isn't.
+    recorder.start(30);
+    recorder.start_fetch("https://example.com/a.js".to_string());
+    recorder.enter(Phase::LoadModule, Some("b".to_string()), 25);
+    recorder.enter(Phase::Execute, None, 20);
+    recorder.finish_fetch("https://example.com/a.js".to_string());
+    recorder.enter(
+        Phase::LoadModule,
+        Some("https://example.com/a.js".to_string()),
+        20,
+    );
+    let usage = recorder.finish(15);
+    let fetched = usage
+        .iter()
+        .find(|usage| usage.module.as_deref() == Some("https://example.com/a.js"))
+        .unwrap();
+    assert_eq!(fetched.fuel_consumed, 5);
+    assert!(fetched.fetch_duration.is_some());
+    assert_eq!(
+        usage.iter().map(|usage| usage.fuel_consumed).sum::<u64>(),
+        15
+    );
+}
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+use wasmtime::component::Component;
+use wasmtime::{AsContext, GuestProfiler};
+
+/// Samples the guest's call stack every `SandboxEngineConfig::profile_interval`,
+/// using wasmtime's guest profiler.
+///
+/// JavaScript is interpreted by the SpiderMonkey engine compiled to wasm, so
+/// most frames are SpiderMonkey's own functions rather than JavaScript
+/// functions. They still show whether the time went on parsing, running
+/// bytecode, regular expressions, garbage collection or host calls.
+///
+/// The root frame of each sample is the sandbox export that was called, which
+/// is named after the WIT export and runs the JavaScript function of the same
+/// name in `sandbox-host-code.js`. For example, samples taken during
+/// `SandboxEngine::evaluate` sit under an `evaluate` frame, which is the
+/// `evaluate` function there. Frames within the user's code can't be resolved
+/// to JavaScript functions, since the guest can't be re-entered to ask for its
+/// stack while it's interrupted.
+pub(crate) struct Profiler {
+    profiler: GuestProfiler,
+    component: Component,
+    interval: Duration,
+    last_sample: Instant,
+}
+
+impl Profiler {
+    pub fn new(component: &Component, interval: Duration) -> wasmtime::Result<Self> {
+        Ok(Self {
+            profiler: new_guest_profiler(component, interval)?,
+            component: component.clone(),
+            interval,
+            last_sample: Instant::now(),
+        })
+    }
+    pub fn sample(&mut self, store: impl AsContext) {
+        let now = Instant::now();
+        self.profiler
+            .sample(store, now.saturating_duration_since(self.last_sample));
+        self.last_sample = now;
+    }
+    /// Start timing the next sample from now, so the time between calls to a
+    /// session isn't counted.
+    pub fn start(&mut self) {
+        self.last_sample = Instant::now();
+    }
+    /// The samples taken since the last call to `finish`, in the Firefox
+    /// Profiler's JSON format.
+    pub fn finish(&mut self) -> wasmtime::Result<Vec<u8>> {
+        let profiler = std::mem::replace(
+            &mut self.profiler,
+            new_guest_profiler(&self.component, self.interval)?,
+        );
+        let mut profile = vec![];
+        profiler.finish(&mut profile)?;
+        Ok(profile)
+    }
+}
+
+fn new_guest_profiler(
+    component: &Component,
+    interval: Duration,
+) -> wasmtime::Result<GuestProfiler> {
+    GuestProfiler::new_component(
+        component.engine(),
+        "sandbox",
+        interval,
+        component.clone(),
+        [],
+    )
+}
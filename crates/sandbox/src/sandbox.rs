@@ -1,15 +1,36 @@
 use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
 use wasmtime::component::{Component, Linker};
-use wasmtime::{Config, Engine, Store};
-use wasmtime_wasi::p2::pipe::MemoryOutputPipe;
+use wasmtime::{
+    Config, Engine, InstanceAllocationStrategy, PoolingAllocationConfig, Store, StoreContextMut,
+    Trap, UpdateDeadline,
+};
+use wasmtime_wasi::p2::bindings::clocks::monotonic_clock;
 use wasmtime_wasi::{ResourceTable, WasiCtx};
 use wasmtime_wasi_http::WasiHttpCtx;
 
+use crate::batch::{BatchBudget, BatchCall, ModuleCall, SandboxBatchResult};
+use crate::clocks::{MonotonicClock, VirtualTime};
+use crate::determinism::Determinism;
+use crate::host_functions::{HostCall, HostFunctions};
 use crate::http::OutboundRequest;
+use crate::js_error::{JavaScriptError, parse_stack};
+use crate::kv::{KvConfig, KvState};
+use crate::output::{LogRecord, LogRecorder, OutputKind, OutputSink, TeeOutputPipe};
+use crate::phases::{Phase, PhaseRecorder, PhaseUsage};
+use crate::profile::Profiler;
+use crate::secrets::Secrets;
 use crate::shared_vec::SharedVec;
 use crate::state::{SandboxHttpState, SandboxState};
+use crate::stream::{StreamConfig, StreamState};
+use crate::value::{ResultLimit, ResultLimits, SandboxValue, ValueEncoding};
 use crate::{
-    CpuFuel, CustomHttpMode, CustomImportMap, HttpMode, ImportMap, MemoryLimits, RequestLimit,
+    CpuFuel, CustomHttpMode, CustomImportMap, HttpMode, ImportMap, InstancePoolLimits,
+    MemoryLimits, RequestLimit, ResourceLimit, WallTimeLimit,
 };
 
 mod bindings {
@@ -25,7 +46,10 @@ mod bindings {
 }
 
 pub(crate) use bindings::local::host::host_impl::Host;
+pub(crate) use bindings::local::host::host_impl::LogLevel;
 pub use bindings::local::host::host_impl::ResolvedModule;
+pub(crate) use bindings::local::host::host_impl::Value;
+use bindings::local::host::phases::Phase as GuestPhase;
 
 #[derive(Clone)]
 pub struct SandboxConfig<
@@ -46,6 +70,40 @@ pub struct SandboxConfig<
     /// Whether to strip TypeScript type annotations from the code before evaluating - if it's a module, only the initial module has types stripped.
     pub strip_typescript_types: bool,
     pub filename: Option<String>,
+    /// How JSON parameters and results represent values such as `undefined`,
+    /// `BigInt`, `Date`, `Map` and `Set`.
+    pub value_encoding: ValueEncoding,
+    /// Limit the size and nesting depth of the returned value.
+    pub result_limits: ResultLimits,
+    /// Limit the wall-clock time the sandbox can run for, including time spent
+    /// waiting for timers and outbound requests.
+    pub wall_time_limit: WallTimeLimit,
+    /// Send stdout and stderr here as soon as they are written, in addition
+    /// to returning them in the `SandboxEvaluationResult`.
+    pub output_sink: Option<OutputSink>,
+    /// If the function returns an async iterator, send the values it yields
+    /// here as they are produced, instead of returning the iterator itself.
+    pub stream: Option<StreamConfig>,
+    /// Use fixed clocks and seeded random number generators, so that the
+    /// evaluation can be reproduced.
+    pub determinism: Option<Determinism>,
+    /// Fast-forward the clocks to the next timer whenever the sandbox is only
+    /// waiting for timers, instead of waiting in real time. Time stands still
+    /// while outbound requests wait for their headers, and while the guest
+    /// waits to read more of a response body.
+    pub virtual_time: bool,
+    /// Give the sandbox a namespace in a key-value store, as the `kv` global.
+    pub kv: Option<KvConfig>,
+    /// Values the sandbox can read from the `secrets` global, or use as
+    /// `{{secret:name}}` placeholders in request headers if they are bound to
+    /// hosts. They are redacted from the output, errors, streamed items and
+    /// outbound request URIs.
+    pub secrets: Secrets,
+    /// Sample the guest's call stack every `SandboxEngineConfig::profile_interval`
+    /// and return the samples in `SandboxEvaluationResult::profile`. This
+    /// slows the sandbox down, so it's meant for finding out where the fuel
+    /// went rather than for every evaluation.
+    pub profile: bool,
 }
 impl Default for SandboxConfig {
     fn default() -> Self {
@@ -58,6 +116,103 @@ impl Default for SandboxConfig {
             mode: EvaluateMode::default(),
             strip_typescript_types: false,
             filename: None,
+            value_encoding: ValueEncoding::default(),
+            result_limits: ResultLimits::default(),
+            wall_time_limit: WallTimeLimit::default(),
+            output_sink: None,
+            stream: None,
+            determinism: None,
+            virtual_time: false,
+            kv: None,
+            secrets: Secrets::default(),
+            profile: false,
+        }
+    }
+}
+impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap> SandboxConfig<THttpMode, TImportMap> {
+    fn split(self) -> (InstanceConfig<THttpMode, TImportMap>, EvaluateOptions) {
+        let stream = self.stream.is_some();
+        (
+            InstanceConfig {
+                cpu_fuel: self.cpu_fuel,
+                memory_limits: self.memory_limits,
+                http: self.http,
+                imports: self.imports,
+                request_limit: self.request_limit,
+                wall_time_limit: self.wall_time_limit,
+                output_sink: self.output_sink,
+                stream: self
+                    .stream
+                    .map(|stream| StreamState::new(stream, self.result_limits)),
+                determinism: self.determinism,
+                virtual_time: self.virtual_time,
+                kv: self.kv,
+                secrets: self.secrets,
+                profile: self.profile,
+            },
+            EvaluateOptions {
+                mode: self.mode,
+                strip_typescript_types: self.strip_typescript_types,
+                filename: self.filename,
+                value_encoding: self.value_encoding,
+                result_limits: self.result_limits,
+                stream,
+            },
+        )
+    }
+}
+
+/// The parts of a `SandboxConfig` that are used to build an instance.
+struct InstanceConfig<THttpMode: CustomHttpMode, TImportMap: CustomImportMap> {
+    cpu_fuel: CpuFuel,
+    memory_limits: MemoryLimits,
+    http: THttpMode,
+    imports: TImportMap,
+    request_limit: RequestLimit,
+    wall_time_limit: WallTimeLimit,
+    output_sink: Option<OutputSink>,
+    stream: Option<StreamState>,
+    determinism: Option<Determinism>,
+    virtual_time: bool,
+    kv: Option<KvConfig>,
+    secrets: Secrets,
+    profile: bool,
+}
+
+/// Settings that apply to every sandbox created by a `SandboxEngine`.
+#[derive(Clone)]
+pub struct SandboxEngineConfig {
+    /// Use wasmtime's pooling allocator with these pool sizes, instead of
+    /// allocating memory on demand for each sandbox.
+    pub instance_pool: Option<InstancePoolLimits>,
+    /// How often running sandboxes check whether they have exceeded their
+    /// `wall_time_limit`.
+    pub epoch_interval: Duration,
+    /// How often sandboxes with `SandboxConfig::profile` set sample their call
+    /// stack. If this is shorter than `epoch_interval`, the epoch ticks at this
+    /// rate instead, but sandboxes that aren't profiled still only check their
+    /// deadline every `epoch_interval`.
+    pub profile_interval: Duration,
+    /// How much fuel a sandbox can consume before yielding to the async
+    /// runtime, so that CPU-bound scripts don't block other tasks on the same
+    /// thread. Set this to `None` to never yield.
+    pub fuel_yield_interval: Option<u64>,
+    /// Run sandboxes on a dedicated runtime with this many worker threads,
+    /// instead of the runtime that called `SandboxEngine::evaluate`. This
+    /// includes sessions, which keep their instance on a task there.
+    pub compute_threads: Option<usize>,
+    /// Functions that JavaScript code can call using the `host` global.
+    pub host_functions: Option<Arc<dyn HostFunctions>>,
+}
+impl Default for SandboxEngineConfig {
+    fn default() -> Self {
+        Self {
+            instance_pool: None,
+            epoch_interval: Duration::from_millis(10),
+            profile_interval: Duration::from_millis(1),
+            fuel_yield_interval: Some(1_000_000),
+            compute_threads: None,
+            host_functions: None,
         }
     }
 }
@@ -68,19 +223,61 @@ pub struct SandboxEngine<
 > {
     engine: Engine,
     component: Component,
-    linker: Linker<SandboxState<TImportMap, THttpMode>>,
+    pre: bindings::RootPre<SandboxState<TImportMap, THttpMode>>,
+    profile_interval: Duration,
+    deadline_ticks: u64,
+    fuel_yield_interval: Option<u64>,
+    compute_runtime: Option<Arc<ComputeRuntime>>,
+    host_functions: Option<Arc<dyn HostFunctions>>,
+}
+
+impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap> Clone
+    for SandboxEngine<THttpMode, TImportMap>
+{
+    fn clone(&self) -> Self {
+        Self {
+            engine: self.engine.clone(),
+            component: self.component.clone(),
+            pre: self.pre.clone(),
+            profile_interval: self.profile_interval,
+            deadline_ticks: self.deadline_ticks,
+            fuel_yield_interval: self.fuel_yield_interval,
+            compute_runtime: self.compute_runtime.clone(),
+            host_functions: self.host_functions.clone(),
+        }
+    }
 }
 
 impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap> SandboxEngine<THttpMode, TImportMap> {
     pub fn new() -> anyhow::Result<Self> {
+        Self::with_config(&SandboxEngineConfig::default())
+    }
+
+    pub fn with_config(config: &SandboxEngineConfig) -> anyhow::Result<Self> {
         let mut engine_config = Config::new();
         // engine_config.cache_config_load_default().unwrap();
         // engine_config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
         engine_config.consume_fuel(true);
+        engine_config.epoch_interruption(true);
+        // Each instance starts from a copy-on-write mapping of the component's
+        // initial memory image, rather than copying the SpiderMonkey heap.
+        engine_config.memory_init_cow(true);
+        if let Some(instance_pool) = &config.instance_pool {
+            engine_config.allocation_strategy(InstanceAllocationStrategy::Pooling(
+                pooling_allocation_config(instance_pool),
+            ));
+        }
 
         // An engine stores and configures global compilation settings like
         // optimization level, enabled wasm features, etc.
         let engine = Engine::new(&engine_config)?;
+        let tick = config.epoch_interval.min(config.profile_interval);
+        start_epoch_ticker(&engine, tick)?;
+        // The number of ticks between deadline checks when not profiling
+        let deadline_ticks =
+            u64::try_from(config.epoch_interval.as_nanos() / tick.as_nanos().max(1))
+                .unwrap_or(u64::MAX)
+                .max(1);
         let mut linker: Linker<SandboxState<TImportMap, THttpMode>> = Linker::new(&engine);
 
         // Wasi Provides support for accessing system APIs from the sandbox.
@@ -89,143 +286,870 @@ impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap> SandboxEngine<THttp
         // to work from within JavaScript.
         wasmtime_wasi::p2::add_to_linker_async(&mut linker)?;
         wasmtime_wasi_http::p2::add_only_http_to_linker_async(&mut linker)?;
+        // Replace wasmtime-wasi's monotonic clock, so timers can use virtual
+        // time.
+        linker.allow_shadowing(true);
+        monotonic_clock::add_to_linker::<
+            SandboxState<TImportMap, THttpMode>,
+            SandboxState<TImportMap, THttpMode>,
+        >(&mut linker, |s| s)?;
+        linker.allow_shadowing(false);
         bindings::local::host::host_impl::add_to_linker::<
             SandboxState<TImportMap, THttpMode>,
             SandboxState<TImportMap, THttpMode>,
         >(&mut linker, |s| s)?;
+        // Added by hand rather than with the bindings' `add_to_linker`, as
+        // it needs the store to read the fuel.
+        let mut phases = linker.instance("local:host/phases")?;
+        phases.func_wrap(
+            "enter-phase",
+            |mut store: StoreContextMut<'_, SandboxState<TImportMap, THttpMode>>,
+             (phase, module): (GuestPhase, Option<String>)| {
+                let fuel = store.get_fuel()?;
+                store.data_mut().phases.enter(phase.into(), module, fuel);
+                Ok(())
+            },
+        )?;
+        phases.func_wrap(
+            "start-fetch",
+            |mut store: StoreContextMut<'_, SandboxState<TImportMap, THttpMode>>,
+             (module,): (String,)| {
+                store.data_mut().phases.start_fetch(module);
+                Ok(())
+            },
+        )?;
+        phases.func_wrap(
+            "finish-fetch",
+            |mut store: StoreContextMut<'_, SandboxState<TImportMap, THttpMode>>,
+             (module,): (String,)| {
+                store.data_mut().phases.finish_fetch(module);
+                Ok(())
+            },
+        )?;
 
         let component: Component =
             unsafe { Component::deserialize(&engine, include_bytes!("sandbox/sandbox.bin"))? };
 
+        // Resolve all the imports once up front, so each evaluation only
+        // needs to instantiate the component.
+        let pre = bindings::RootPre::new(linker.instantiate_pre(&component)?)?;
+
+        let compute_runtime = match config.compute_threads {
+            Some(threads) => Some(Arc::new(ComputeRuntime::new(threads)?)),
+            None => None,
+        };
+
         Ok(Self {
             engine,
             component,
-            linker,
+            pre,
+            profile_interval: config.profile_interval,
+            deadline_ticks,
+            fuel_yield_interval: config.fuel_yield_interval,
+            compute_runtime,
+            host_functions: config.host_functions.clone(),
         })
     }
 
     async fn build(
         &self,
-        cpu_fuel: CpuFuel,
-        memory_limits: MemoryLimits,
-        http: THttpMode,
-        imports: TImportMap,
-        request_limit: RequestLimit,
+        config: InstanceConfig<THttpMode, TImportMap>,
+        cancel: CancellationToken,
     ) -> wasmtime::Result<SandboxInstance<THttpMode, TImportMap>> {
-        let stdout = MemoryOutputPipe::new(memory_limits.stdout_bytes.into());
-        let stderr = MemoryOutputPipe::new(memory_limits.stderr_bytes.into());
-        let ctx: WasiCtx = WasiCtx::builder()
-            .stdout(stdout.clone())
-            .stderr(stderr.clone())
-            .build();
+        let deadline = config
+            .wall_time_limit
+            .as_duration()
+            .map(|limit| Instant::now() + limit);
+        let output_tees = [
+            TeeOutputPipe::new(
+                config.memory_limits.stdout_bytes.into(),
+                OutputKind::Stdout,
+                config.output_sink.clone(),
+                config.secrets.clone(),
+            ),
+            TeeOutputPipe::new(
+                config.memory_limits.stderr_bytes.into(),
+                OutputKind::Stderr,
+                config.output_sink,
+                config.secrets.clone(),
+            ),
+        ];
+        let mut builder = WasiCtx::builder();
+        builder
+            .stdout(output_tees[0].clone())
+            .stderr(output_tees[1].clone());
+        let mut monotonic_clock = MonotonicClock::real();
+        let mut seed = None;
+        if let Some(determinism) = &config.determinism {
+            let (determinism_seed, clock) = determinism.apply(&mut builder);
+            seed = Some(determinism_seed);
+            monotonic_clock = clock;
+        }
+        let virtual_time = config.virtual_time.then(VirtualTime::default);
+        if let Some(virtual_time) = &virtual_time {
+            let start = match config
+                .determinism
+                .as_ref()
+                .and_then(Determinism::start_time)
+            {
+                Some(start) => start,
+                None => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default(),
+            };
+            builder.wall_clock(virtual_time.wall_clock(start));
+            monotonic_clock = MonotonicClock::Virtual(virtual_time.clone());
+        }
+        let ctx: WasiCtx = builder.build();
+        let logs = LogRecorder::new(output_tees[0].clone(), output_tees[1].clone());
+        let profiler = if config.profile {
+            Some(Profiler::new(&self.component, self.profile_interval)?)
+        } else {
+            None
+        };
         let mut store = Store::new(
             &self.engine,
             SandboxState {
                 wasi_ctx: ctx,
                 wasi_http: WasiHttpCtx::new(),
                 resource_table: ResourceTable::default(),
-                memory_limits,
+                memory_limits: config.memory_limits,
                 http: SandboxHttpState {
-                    http,
-                    request_limit,
+                    http: config.http,
+                    request_limit: config.request_limit,
                     requests: SharedVec::default(),
                     request_count: 0,
+                    deadline,
+                    cancel: cancel.clone(),
+                    virtual_time: virtual_time.clone(),
+                    secrets: config.secrets.clone(),
                 },
-                imports,
+                imports: config.imports,
+                logs,
+                monotonic_clock,
+                host_functions: self.host_functions.clone(),
+                host_calls: vec![],
+                kv: config.kv.map(KvState::new),
+                stream: config.stream,
+                profiler,
+                phases: PhaseRecorder::new(config.cpu_fuel.into()),
                 max_requested_memory_bytes: None,
                 max_requested_table_elements: None,
             },
         );
         store.limiter(|s| s);
-        store.set_fuel(cpu_fuel.into())?;
-        let sandbox =
-            bindings::Root::instantiate_async(&mut store, &self.component, &self.linker).await?;
+        store.set_fuel(config.cpu_fuel.into())?;
+        store.fuel_async_yield_interval(self.fuel_yield_interval)?;
+        // Profiled sandboxes wake on every tick to take a sample
+        let ticks = if config.profile {
+            1
+        } else {
+            self.deadline_ticks
+        };
+        store.set_epoch_deadline(ticks);
+        store.epoch_deadline_callback(move |mut store| {
+            if let Some(mut profiler) = store.data_mut().profiler.take() {
+                profiler.sample(&store);
+                store.data_mut().profiler = Some(profiler);
+            }
+            let http = &store.data().http;
+            if http.cancel.is_cancelled()
+                || http
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                Err(Trap::Interrupt.into())
+            } else {
+                Ok(UpdateDeadline::Continue(ticks))
+            }
+        });
+        let sandbox = self.pre.instantiate_async(&mut store).await?;
+        let fuel = store.get_fuel()?;
+        store.data_mut().phases.enter(Phase::Execute, None, fuel);
         Ok(SandboxInstance {
             sandbox,
             store,
-            stdout,
-            stderr,
+            output_tees,
+            deadline,
+            cancel,
+            trapped: false,
+            seed,
+            virtual_time,
+            virtual_time_start: 0,
+            secrets: config.secrets,
         })
     }
     pub async fn evaluate(
         &self,
         code: &str,
-        parameters: &[serde_json::Value],
+        parameters: &[SandboxValue],
         config: SandboxConfig<THttpMode, TImportMap>,
     ) -> SandboxEvaluationResult {
-        match self
-            .build(
-                config.cpu_fuel,
-                config.memory_limits,
-                config.http,
-                config.imports,
-                config.request_limit,
-            )
+        self.evaluate_cancellable(code, parameters, config, &CancellationToken::new())
             .await
-        {
-            Ok(instance) => {
-                instance
-                    .evaluate(
-                        code,
-                        parameters,
-                        &EvaluateOptions {
-                            mode: config.mode,
-                            strip_typescript_types: config.strip_typescript_types,
-                            filename: config.filename,
-                        },
+    }
+
+    /// Like `evaluate`, but stops the sandbox and aborts any outstanding
+    /// outbound requests once `cancel` is cancelled. The result then has an
+    /// `EvaluateError::Cancelled` error, along with any output written so far.
+    pub async fn evaluate_cancellable(
+        &self,
+        code: &str,
+        parameters: &[SandboxValue],
+        config: SandboxConfig<THttpMode, TImportMap>,
+        cancel: &CancellationToken,
+    ) -> SandboxEvaluationResult {
+        let Some(compute_runtime) = &self.compute_runtime else {
+            return self
+                .evaluate_on_current_runtime(code, parameters, config, cancel)
+                .await;
+        };
+        // Dropping the `JoinHandle` does not stop the task, so cancel it if
+        // this future is dropped before it completes.
+        let cancel = cancel.child_token();
+        let task_cancel = cancel.clone();
+        let _cancel_on_drop = cancel.drop_guard();
+        let engine = self.clone();
+        let code = code.to_owned();
+        let parameters = parameters.to_vec();
+        compute_runtime
+            .handle
+            .spawn(async move {
+                engine
+                    .evaluate_on_current_runtime(&code, &parameters, config, &task_cancel)
+                    .await
+            })
+            .await
+            .unwrap_or_else(|err| {
+                SandboxEvaluationResult::from_error(wasmtime::format_err!(
+                    "Sandbox task failed: {err}"
+                ))
+            })
+    }
+
+    async fn evaluate_on_current_runtime(
+        &self,
+        code: &str,
+        parameters: &[SandboxValue],
+        config: SandboxConfig<THttpMode, TImportMap>,
+        cancel: &CancellationToken,
+    ) -> SandboxEvaluationResult {
+        let (instance_config, options) = config.split();
+        match self.build(instance_config, cancel.clone()).await {
+            Ok(mut instance) => instance.evaluate(code, parameters, &options).await,
+            Err(err) => SandboxEvaluationResult::from_error(err),
+        }
+    }
+
+    /// Start a sandbox that can be called repeatedly using
+    /// `SandboxSession::evaluate`. The instance lives on a task of its own,
+    /// on the compute runtime if the engine has one, and each call is sent
+    /// to it.
+    pub async fn open_session(
+        &self,
+        config: SandboxConfig<THttpMode, TImportMap>,
+    ) -> Result<SandboxSession, EvaluateError> {
+        let (opened, on_opened) = tokio::sync::oneshot::channel();
+        let (calls, receiver) = tokio::sync::mpsc::channel(1);
+        let state = Arc::new(SessionState::default());
+        let task = run_session(self.clone(), config, opened, receiver, state.clone());
+        let runtime = self
+            .compute_runtime
+            .as_ref()
+            .map_or_else(tokio::runtime::Handle::current, |runtime| {
+                runtime.handle.clone()
+            });
+        runtime.spawn(task);
+        on_opened.await.map_err(|err| {
+            EvaluateError::WasmError(wasmtime::format_err!("Sandbox task failed: {err}"))
+        })??;
+        Ok(SandboxSession { calls, state })
+    }
+}
+
+struct SessionCall {
+    code: String,
+    parameters: Vec<SandboxValue>,
+    cancel: CancellationToken,
+    result: tokio::sync::oneshot::Sender<SandboxEvaluationResult>,
+}
+
+/// What the session's task last reported, so that it can be read without
+/// waiting for a call to finish.
+#[derive(Default)]
+struct SessionState {
+    terminated: AtomicBool,
+    fuel_remaining: AtomicU64,
+}
+
+impl SessionState {
+    fn update<THttpMode: CustomHttpMode, TImportMap: CustomImportMap>(
+        &self,
+        instance: &SandboxInstance<THttpMode, TImportMap>,
+    ) {
+        self.terminated.store(instance.trapped, Ordering::Relaxed);
+        self.fuel_remaining
+            .store(instance.store.get_fuel().unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+/// Owns a session's instance, and runs each call sent to it in turn until the
+/// `SandboxSession` is dropped.
+async fn run_session<THttpMode: CustomHttpMode, TImportMap: CustomImportMap>(
+    engine: SandboxEngine<THttpMode, TImportMap>,
+    config: SandboxConfig<THttpMode, TImportMap>,
+    opened: tokio::sync::oneshot::Sender<Result<(), EvaluateError>>,
+    mut calls: tokio::sync::mpsc::Receiver<SessionCall>,
+    state: Arc<SessionState>,
+) {
+    let wall_time_limit = config.wall_time_limit;
+    let (instance_config, options) = config.split();
+    let mut instance = match engine
+        .build(instance_config, CancellationToken::new())
+        .await
+    {
+        Ok(instance) => instance,
+        Err(err) => {
+            let _ = opened.send(Err(err));
+            return;
+        }
+    };
+    state.update(&instance);
+    if opened.send(Ok(())).is_err() {
+        return;
+    }
+    while let Some(call) = calls.recv().await {
+        let result = if instance.trapped {
+            instance.handle_error(EvaluateError::SessionTerminated)
+        } else {
+            instance.start_call(wall_time_limit, call.cancel);
+            instance
+                .evaluate(&call.code, &call.parameters, &options)
+                .await
+        };
+        state.update(&instance);
+        // The caller may have stopped waiting, in which case the call was
+        // cancelled and the session is terminated.
+        let _ = call.result.send(result);
+    }
+}
+
+/// A sandbox that is kept alive between calls, so that globals set by one
+/// call are still available to the next. The fuel, memory and request limits
+/// apply to the session as a whole, while `wall_time_limit` and the output
+/// limits apply to each call.
+///
+/// Once a call traps, e.g. by running out of fuel or time, the session can't
+/// be used again and further calls return `EvaluateError::SessionTerminated`.
+pub struct SandboxSession {
+    calls: tokio::sync::mpsc::Sender<SessionCall>,
+    state: Arc<SessionState>,
+}
+
+impl SandboxSession {
+    pub async fn evaluate(
+        &mut self,
+        code: &str,
+        parameters: &[SandboxValue],
+    ) -> SandboxEvaluationResult {
+        self.evaluate_cancellable(code, parameters, &CancellationToken::new())
+            .await
+    }
+
+    /// Like `evaluate`, but stops the call once `cancel` is cancelled, or this
+    /// future is dropped. This also terminates the session.
+    pub async fn evaluate_cancellable(
+        &mut self,
+        code: &str,
+        parameters: &[SandboxValue],
+        cancel: &CancellationToken,
+    ) -> SandboxEvaluationResult {
+        let cancel = cancel.child_token();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let (result, on_result) = tokio::sync::oneshot::channel();
+        let call = SessionCall {
+            code: code.to_owned(),
+            parameters: parameters.to_vec(),
+            cancel,
+            result,
+        };
+        if self.calls.send(call).await.is_err() {
+            return SandboxEvaluationResult::from_error(EvaluateError::SessionTerminated);
+        }
+        on_result.await.unwrap_or_else(|err| {
+            SandboxEvaluationResult::from_error(wasmtime::format_err!("Sandbox task failed: {err}"))
+        })
+    }
+
+    #[must_use]
+    pub fn is_terminated(&self) -> bool {
+        self.state.terminated.load(Ordering::Relaxed)
+    }
+
+    /// The fuel left for the rest of the session.
+    #[must_use]
+    pub fn fuel_remaining(&self) -> u64 {
+        self.state.fuel_remaining.load(Ordering::Relaxed)
+    }
+}
+
+impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap + Clone>
+    SandboxEngine<THttpMode, TImportMap>
+{
+    /// Strip types from `code` and load and compile its static imports, so
+    /// that it can be invoked repeatedly without repeating that work.
+    pub async fn parse(
+        &self,
+        code: &str,
+        config: SandboxConfig<THttpMode, TImportMap>,
+    ) -> Result<ParsedScript<THttpMode, TImportMap>, EvaluateError> {
+        let (script, outbound_requests) = match &self.compute_runtime {
+            None => self.parse_on_current_runtime(code, &config).await?,
+            Some(compute_runtime) => {
+                let engine = self.clone();
+                let code = code.to_owned();
+                let task_config = config.clone();
+                compute_runtime
+                    .handle
+                    .spawn(
+                        async move { engine.parse_on_current_runtime(&code, &task_config).await },
                     )
                     .await
+                    .map_err(|err| {
+                        EvaluateError::WasmError(wasmtime::format_err!(
+                            "Sandbox task failed: {err}"
+                        ))
+                    })??
             }
-            Err(err) => SandboxEvaluationResult {
-                result: Err(EvaluateError::WasmError(err)),
-                stdout: String::new(),
-                stderr: String::new(),
-                fuel_remaining: 0,
-                max_requested_memory_bytes: None,
-                max_requested_table_elements: None,
-                outbound_requests: vec![],
-            },
+        };
+        Ok(ParsedScript {
+            engine: self.clone(),
+            script: Arc::new(script),
+            config,
+            outbound_requests,
+        })
+    }
+
+    async fn parse_on_current_runtime(
+        &self,
+        code: &str,
+        config: &SandboxConfig<THttpMode, TImportMap>,
+    ) -> Result<(bindings::PreparedScript, Vec<OutboundRequest>), EvaluateError> {
+        let (instance_config, options) = config.clone().split();
+        let mut instance = self
+            .build(instance_config, CancellationToken::new())
+            .await?;
+        instance.parse(code, &options).await
+    }
+
+    /// Load `code` once, then call it with each list of parameters in turn,
+    /// in the same sandbox. Globals set by one item are visible to the next.
+    pub async fn evaluate_batch(
+        &self,
+        code: &str,
+        batch: &[Vec<SandboxValue>],
+        config: SandboxConfig<THttpMode, TImportMap>,
+        budget: BatchBudget,
+    ) -> SandboxBatchResult {
+        self.evaluate_batch_cancellable(code, batch, config, budget, &CancellationToken::new())
+            .await
+    }
+
+    /// Like `evaluate_batch`, but stops once `cancel` is cancelled. The rest
+    /// of the items then fail with `EvaluateError::Cancelled`.
+    pub async fn evaluate_batch_cancellable(
+        &self,
+        code: &str,
+        batch: &[Vec<SandboxValue>],
+        config: SandboxConfig<THttpMode, TImportMap>,
+        budget: BatchBudget,
+        cancel: &CancellationToken,
+    ) -> SandboxBatchResult {
+        let method = match &config.mode {
+            EvaluateMode::ModuleMethod(method) => Some(method.clone()),
+            EvaluateMode::FunctionCall => None,
+        };
+        let calls = batch
+            .iter()
+            .map(|parameters| BatchCall {
+                method: method.clone(),
+                parameters: parameters.clone(),
+            })
+            .collect();
+        self.evaluate_calls(code, method.is_some(), calls, config, budget, cancel)
+            .await
+    }
+
+    /// Evaluate `code` as a module once, then call each of `calls` in turn.
+    /// Each call gets its own result and output, while the fuel and wall time
+    /// limits apply to the calls as a whole. `config.mode` is ignored.
+    pub async fn evaluate_module_calls(
+        &self,
+        code: &str,
+        calls: &[ModuleCall],
+        config: SandboxConfig<THttpMode, TImportMap>,
+    ) -> SandboxBatchResult {
+        self.evaluate_module_calls_cancellable(code, calls, config, &CancellationToken::new())
+            .await
+    }
+
+    /// Like `evaluate_module_calls`, but stops once `cancel` is cancelled.
+    pub async fn evaluate_module_calls_cancellable(
+        &self,
+        code: &str,
+        calls: &[ModuleCall],
+        config: SandboxConfig<THttpMode, TImportMap>,
+        cancel: &CancellationToken,
+    ) -> SandboxBatchResult {
+        let calls = calls
+            .iter()
+            .map(|call| BatchCall {
+                method: Some(call.method.clone()),
+                parameters: call.parameters.clone(),
+            })
+            .collect();
+        self.evaluate_calls(code, true, calls, config, BatchBudget::Aggregate, cancel)
+            .await
+    }
+
+    async fn evaluate_calls(
+        &self,
+        code: &str,
+        is_module: bool,
+        calls: Vec<BatchCall>,
+        config: SandboxConfig<THttpMode, TImportMap>,
+        budget: BatchBudget,
+        cancel: &CancellationToken,
+    ) -> SandboxBatchResult {
+        let Some(compute_runtime) = &self.compute_runtime else {
+            return self
+                .evaluate_calls_on_current_runtime(code, is_module, &calls, config, budget, cancel)
+                .await;
+        };
+        let cancel = cancel.child_token();
+        let task_cancel = cancel.clone();
+        let _cancel_on_drop = cancel.drop_guard();
+        let engine = self.clone();
+        let code = code.to_owned();
+        compute_runtime
+            .handle
+            .spawn(async move {
+                engine
+                    .evaluate_calls_on_current_runtime(
+                        &code,
+                        is_module,
+                        &calls,
+                        config,
+                        budget,
+                        &task_cancel,
+                    )
+                    .await
+            })
+            .await
+            .unwrap_or_else(|err| SandboxBatchResult {
+                load: SandboxEvaluationResult::from_error(wasmtime::format_err!(
+                    "Sandbox task failed: {err}"
+                )),
+                items: vec![],
+            })
+    }
+
+    async fn evaluate_calls_on_current_runtime(
+        &self,
+        code: &str,
+        is_module: bool,
+        calls: &[BatchCall],
+        config: SandboxConfig<THttpMode, TImportMap>,
+        budget: BatchBudget,
+        cancel: &CancellationToken,
+    ) -> SandboxBatchResult {
+        let cpu_fuel = config.cpu_fuel;
+        let wall_time_limit = config.wall_time_limit;
+        let (instance_config, options) = config.clone().split();
+        let (instance, load) = self
+            .load_batch(code, is_module, instance_config, &options, cancel)
+            .await;
+        let Some(mut instance) = instance else {
+            return SandboxBatchResult {
+                load,
+                items: vec![],
+            };
+        };
+        let mut items = Vec::with_capacity(calls.len());
+        for call in calls {
+            let mut reload = None;
+            if instance.trapped && budget == BatchBudget::PerItem && !cancel.is_cancelled() {
+                let (reloaded, result) = self
+                    .load_batch(code, is_module, config.clone().split().0, &options, cancel)
+                    .await;
+                match reloaded {
+                    Some(reloaded) => instance = reloaded,
+                    None => {
+                        items.push(result);
+                        continue;
+                    }
+                }
+                reload = Some(result);
+            }
+            let mut item = if instance.trapped {
+                let err = if cancel.is_cancelled() {
+                    EvaluateError::Cancelled
+                } else {
+                    EvaluateError::SessionTerminated
+                };
+                instance.handle_error(err)
+            } else {
+                match budget {
+                    BatchBudget::PerItem => {
+                        instance.start_call(wall_time_limit, cancel.clone());
+                        match instance.store.set_fuel(cpu_fuel.into()) {
+                            Ok(()) => instance.evaluate_batch_item(call, &options).await,
+                            Err(err) => instance.handle_error(err.into()),
+                        }
+                    }
+                    BatchBudget::Aggregate => {
+                        instance.start_item();
+                        instance.evaluate_batch_item(call, &options).await
+                    }
+                }
+            };
+            if let Some(reload) = reload {
+                item.include_reload(reload, cpu_fuel.into());
+            }
+            items.push(item);
         }
+        SandboxBatchResult { load, items }
+    }
+
+    /// Returns the instance if the code was loaded, along with the result of
+    /// loading it.
+    async fn load_batch(
+        &self,
+        code: &str,
+        is_module: bool,
+        config: InstanceConfig<THttpMode, TImportMap>,
+        options: &EvaluateOptions,
+        cancel: &CancellationToken,
+    ) -> (
+        Option<SandboxInstance<THttpMode, TImportMap>>,
+        SandboxEvaluationResult,
+    ) {
+        let mut instance = match self.build(config, cancel.clone()).await {
+            Ok(instance) => instance,
+            Err(err) => return (None, SandboxEvaluationResult::from_error(err)),
+        };
+        let result = instance.load_batch(code, is_module, options).await;
+        (result.result.is_ok().then_some(instance), result)
     }
 }
 
+/// A script returned by `SandboxEngine::parse`, with its types stripped and
+/// its static imports loaded and compiled. Each call to `invoke` runs in a
+/// fresh sandbox, and SpiderMonkey still evaluates the code and the top level
+/// of each module every time, so this only saves work for TypeScript and for
+/// modules with imports. `benches/parsed_script.rs` measures the difference.
+pub struct ParsedScript<
+    THttpMode: CustomHttpMode = HttpMode,
+    TImportMap: CustomImportMap = ImportMap,
+> {
+    engine: SandboxEngine<THttpMode, TImportMap>,
+    script: Arc<bindings::PreparedScript>,
+    config: SandboxConfig<THttpMode, TImportMap>,
+    outbound_requests: Vec<OutboundRequest>,
+}
+
+impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap + Clone>
+    ParsedScript<THttpMode, TImportMap>
+{
+    /// The requests made while loading imports from URLs. Calls to `invoke`
+    /// don't make them again.
+    #[must_use]
+    pub fn outbound_requests(&self) -> &[OutboundRequest] {
+        &self.outbound_requests
+    }
+
+    /// Runs on the engine's compute runtime, if it has one, like
+    /// `SandboxEngine::evaluate`.
+    pub async fn invoke(&self, parameters: &[SandboxValue]) -> SandboxEvaluationResult {
+        let Some(compute_runtime) = &self.engine.compute_runtime else {
+            return invoke_on_current_runtime(
+                &self.engine,
+                &self.script,
+                parameters,
+                self.config.clone(),
+            )
+            .await;
+        };
+        let engine = self.engine.clone();
+        let script = self.script.clone();
+        let parameters = parameters.to_vec();
+        let config = self.config.clone();
+        compute_runtime
+            .handle
+            .spawn(async move {
+                invoke_on_current_runtime(&engine, &script, &parameters, config).await
+            })
+            .await
+            .unwrap_or_else(|err| {
+                SandboxEvaluationResult::from_error(wasmtime::format_err!(
+                    "Sandbox task failed: {err}"
+                ))
+            })
+    }
+}
+
+async fn invoke_on_current_runtime<THttpMode: CustomHttpMode, TImportMap: CustomImportMap>(
+    engine: &SandboxEngine<THttpMode, TImportMap>,
+    script: &bindings::PreparedScript,
+    parameters: &[SandboxValue],
+    config: SandboxConfig<THttpMode, TImportMap>,
+) -> SandboxEvaluationResult {
+    let (instance_config, options) = config.split();
+    match engine
+        .build(instance_config, CancellationToken::new())
+        .await
+    {
+        Ok(mut instance) => {
+            instance
+                .evaluate_prepared(script, parameters, &options)
+                .await
+        }
+        Err(err) => SandboxEvaluationResult::from_error(err),
+    }
+}
+
+/// A runtime used only for running sandboxes, so that CPU-bound scripts
+/// cannot delay tasks on the runtime that is serving HTTP requests.
+struct ComputeRuntime {
+    handle: tokio::runtime::Handle,
+    runtime: Option<tokio::runtime::Runtime>,
+}
+
+impl ComputeRuntime {
+    fn new(threads: usize) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(threads)
+            .thread_name("sandbox-compute")
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            handle: runtime.handle().clone(),
+            runtime: Some(runtime),
+        })
+    }
+}
+
+impl Drop for ComputeRuntime {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks until its tasks finish, which panics if
+        // the last engine is dropped from within another runtime.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Increment the engine's epoch every `interval` until the engine is dropped,
+/// so that running sandboxes can check their deadline.
+fn start_epoch_ticker(engine: &Engine, interval: Duration) -> std::io::Result<()> {
+    let engine = engine.weak();
+    std::thread::Builder::new()
+        .name("sandbox-epoch-ticker".into())
+        .spawn(move || {
+            while let Some(engine) = engine.upgrade() {
+                engine.increment_epoch();
+                drop(engine);
+                std::thread::sleep(interval);
+            }
+        })?;
+    Ok(())
+}
+
+fn resource_limit_to_u32(limit: ResourceLimit) -> u32 {
+    u32::try_from(usize::from(limit)).unwrap_or(u32::MAX)
+}
+
+fn pooling_allocation_config(limits: &InstancePoolLimits) -> PoolingAllocationConfig {
+    let max_sandboxes = resource_limit_to_u32(limits.max_sandboxes);
+    let core_instances = resource_limit_to_u32(limits.core_instances_per_sandbox);
+    let memories = resource_limit_to_u32(limits.memories_per_sandbox);
+    let tables = resource_limit_to_u32(limits.tables_per_sandbox);
+    let mut config = PoolingAllocationConfig::default();
+    config
+        .total_component_instances(max_sandboxes)
+        .total_core_instances(max_sandboxes.saturating_mul(core_instances))
+        .total_memories(max_sandboxes.saturating_mul(memories))
+        .total_tables(max_sandboxes.saturating_mul(tables))
+        .total_stacks(max_sandboxes)
+        .max_core_instances_per_component(core_instances)
+        .max_memories_per_component(memories)
+        .max_tables_per_component(tables)
+        .max_memory_size(limits.max_memory_size_bytes.into())
+        .table_elements(limits.table_elements.into());
+    config
+}
+
 #[derive(Debug)]
 pub enum EvaluateError {
     FuelExhausted,
-    JavaScriptError(String),
+    Timeout,
+    Cancelled,
+    SessionTerminated,
+    JavaScriptError(JavaScriptError),
     WasmError(wasmtime::Error),
     JsonError(serde_json::Error),
+    ResultLimitExceeded(ResultLimit),
 }
 
 impl fmt::Display for EvaluateError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             EvaluateError::FuelExhausted => write!(f, "CPU fuel exhausted"),
-            EvaluateError::JavaScriptError(msg) => {
-                write!(f, "JavaScript error: ")?;
-                let mut first = true;
-                for line in msg.lines() {
-                    if !line.contains("sandbox-host-code.js:")
-                        && !line.contains("sources/initializer.js:")
-                    {
-                        if first {
-                            first = false;
-                        } else {
-                            write!(f, "\n  ")?;
-                        }
-                        write!(f, "{line}")?;
-                    }
-                }
-                Ok(())
+            EvaluateError::Timeout => write!(f, "Wall time limit exceeded"),
+            EvaluateError::Cancelled => write!(f, "Evaluation cancelled"),
+            EvaluateError::SessionTerminated => {
+                write!(f, "Session terminated by an earlier error")
             }
+            EvaluateError::JavaScriptError(err) => write!(f, "JavaScript error: {err}"),
             EvaluateError::WasmError(err) => write!(f, "Wasm error: {err}"),
             EvaluateError::JsonError(err) => write!(f, "JSON error: {err}"),
+            EvaluateError::ResultLimitExceeded(limit) => write!(f, "{limit}"),
         }
     }
 }
 impl From<String> for EvaluateError {
     fn from(msg: String) -> Self {
-        EvaluateError::JavaScriptError(msg)
+        EvaluateError::JavaScriptError(JavaScriptError::new(msg))
+    }
+}
+impl From<bindings::JsError> for JavaScriptError {
+    fn from(error: bindings::JsError) -> Self {
+        // The first error is the one that was thrown, followed by its causes.
+        error
+            .errors
+            .into_iter()
+            .rev()
+            .fold(None, |cause, info| {
+                Some(JavaScriptError {
+                    name: info.name,
+                    message: info.message,
+                    stack: info.stack.as_deref().map(parse_stack).unwrap_or_default(),
+                    value: info
+                        .value
+                        .and_then(|value| serde_json::from_str(&value).ok()),
+                    cause: cause.map(Box::new),
+                })
+            })
+            .unwrap_or_else(|| JavaScriptError::new("Unknown error"))
+    }
+}
+impl From<GuestPhase> for Phase {
+    fn from(phase: GuestPhase) -> Self {
+        match phase {
+            GuestPhase::Compile => Phase::Compile,
+            GuestPhase::LoadModule => Phase::LoadModule,
+            GuestPhase::Execute => Phase::Execute,
+        }
     }
 }
 impl From<wasmtime::Error> for EvaluateError {
@@ -242,133 +1166,429 @@ impl From<serde_json::Error> for EvaluateError {
 struct SandboxInstance<THttpMode: CustomHttpMode, TImportMap: CustomImportMap> {
     sandbox: bindings::Root,
     store: wasmtime::Store<crate::state::SandboxState<TImportMap, THttpMode>>,
-    stdout: MemoryOutputPipe,
-    stderr: MemoryOutputPipe,
+    /// The stdout and stderr pipes, which send output to the `OutputSink` if
+    /// there is one.
+    output_tees: [TeeOutputPipe; 2],
+    deadline: Option<Instant>,
+    cancel: CancellationToken,
+    /// Set once a call fails with a trap, after which the instance can't be
+    /// called again.
+    trapped: bool,
+    seed: Option<u64>,
+    virtual_time: Option<VirtualTime>,
+    /// The virtual time when the current call started.
+    virtual_time_start: u64,
+    secrets: Secrets,
 }
 impl<THttpMode: CustomHttpMode, TImportMap: CustomImportMap>
     SandboxInstance<THttpMode, TImportMap>
 {
-    fn handle_result(self, result: Result<(), wasmtime::Error>) -> SandboxEvaluationResult {
-        let full_stdout = take_memory_pipe_contents(self.stdout);
-        let full_stderr = take_memory_pipe_contents(self.stderr);
-        let mut stdout = full_stdout.split("73914D86-55DF-495D-BAD5-B45D571D154D");
-        let stdout_str = stdout.next();
-        let result_str = stdout.next().and_then(|result_str| {
-            result_str
-                .split("8C47F950-3E81-46B1-976E-177A89380038")
-                .next()
-        });
-        let mut stderr = full_stderr.split("E8FEE14A-BBF5-4B08-9E00-6D61189D897D");
-        let stderr_str = stderr.next();
-        let error_str = stderr.next();
-
-        let result = if result.is_err() && self.store.get_fuel().unwrap_or(0) == 0 {
-            Err(crate::EvaluateError::FuelExhausted)
-        } else if let Some(error_str) = error_str {
-            Err(crate::EvaluateError::JavaScriptError(
-                error_str.trim_ascii().to_string(),
-            ))
-        } else if let Some(result_str) = result_str {
-            serde_json::from_str(result_str).map_err(Into::into)
-        } else if let Some(err) = result.err() {
-            Err(crate::EvaluateError::WasmError(err))
+    /// Reset the deadline and cancellation token before calling an instance
+    /// that has already been used.
+    fn start_call(&mut self, wall_time_limit: WallTimeLimit, cancel: CancellationToken) {
+        self.deadline = wall_time_limit
+            .as_duration()
+            .map(|limit| Instant::now() + limit);
+        let state = self.store.data_mut();
+        state.http.deadline = self.deadline;
+        state.http.cancel = cancel.clone();
+        self.cancel = cancel;
+        self.start_item();
+    }
+    /// Reset the logs and virtual time before a call that shares its deadline
+    /// with the previous one.
+    fn start_item(&mut self) {
+        let fuel = self.store.get_fuel().unwrap_or(0);
+        let state = self.store.data_mut();
+        state.logs.start_call();
+        state.phases.start(fuel);
+        if let Some(profiler) = &mut state.profiler {
+            profiler.start();
+        }
+        self.virtual_time_start = self.virtual_time.as_ref().map_or(0, VirtualTime::now);
+    }
+    fn handle_error(&mut self, err: EvaluateError) -> SandboxEvaluationResult {
+        self.flush_output_tees();
+        let mut evaluation = SandboxEvaluationResult {
+            result: Err(err),
+            stdout: String::new(),
+            stderr: String::new(),
+            logs: vec![],
+            logs_truncated: false,
+            seed: self.seed,
+            virtual_time_elapsed: None,
+            fuel_remaining: self.store.get_fuel().unwrap_or(0),
+            max_requested_memory_bytes: self.store.data().max_requested_memory_bytes,
+            max_requested_table_elements: self.store.data().max_requested_table_elements,
+            outbound_requests: self.store.data().http.requests.take(),
+            host_calls: std::mem::take(&mut self.store.data_mut().host_calls),
+            phases: self.take_phases(),
+            profile: self.take_profile(),
+        };
+        evaluation.redact(&self.secrets);
+        evaluation
+    }
+    /// Send the output that the tees held back in case it was part of a
+    /// secret, now that the call won't write any more.
+    fn flush_output_tees(&self) {
+        for tee in &self.output_tees {
+            tee.flush_held_back();
+        }
+    }
+    /// The fuel and time used by each phase of the call that just finished.
+    fn take_phases(&mut self) -> Vec<PhaseUsage> {
+        let fuel = self.store.get_fuel().unwrap_or(0);
+        self.store.data_mut().phases.finish(fuel)
+    }
+    /// The profile of the call that just finished, if profiling is enabled.
+    fn take_profile(&mut self) -> Option<Vec<u8>> {
+        let profiler = self.store.data_mut().profiler.as_mut()?;
+        profiler
+            .finish()
+            .inspect_err(|err| tracing::warn!("failed to write profile: {err}"))
+            .ok()
+    }
+    /// Work out why a call into the sandbox trapped.
+    fn trap_error(&self, err: wasmtime::Error) -> EvaluateError {
+        if self.store.get_fuel().unwrap_or(0) == 0 {
+            EvaluateError::FuelExhausted
+        } else if self.cancel.is_cancelled() {
+            EvaluateError::Cancelled
+        } else if is_interrupt(&err) {
+            EvaluateError::Timeout
         } else {
-            Err(crate::EvaluateError::JavaScriptError(
-                "No result returned from sandbox".to_string(),
-            ))
+            EvaluateError::WasmError(err)
+        }
+    }
+    fn handle_result(
+        &mut self,
+        result: wasmtime::Result<Result<Value, bindings::JsError>>,
+        limits: &ResultLimits,
+    ) -> SandboxEvaluationResult {
+        let result = match result {
+            Ok(Ok(value)) => decode_value(value, limits),
+            Ok(Err(error)) => Err(EvaluateError::JavaScriptError(error.into())),
+            Err(err) => {
+                self.trapped = true;
+                Err(self.trap_error(err))
+            }
         };
-        SandboxEvaluationResult {
+        self.flush_output_tees();
+        let (logs, logs_truncated) = self.store.data_mut().logs.take();
+        let stdout = self.output_tees[0].take_contents();
+        let stderr = self.output_tees[1].take_contents();
+        let mut evaluation = SandboxEvaluationResult {
             result,
-            stdout: stdout_str.unwrap_or("").to_string(),
-            stderr: stderr_str.unwrap_or("").to_string(),
+            stdout,
+            stderr,
+            logs,
+            logs_truncated,
+            seed: self.seed,
+            virtual_time_elapsed: self.virtual_time.as_ref().map(|virtual_time| {
+                Duration::from_nanos(virtual_time.now().saturating_sub(self.virtual_time_start))
+            }),
             fuel_remaining: self.store.get_fuel().unwrap_or(0),
             max_requested_memory_bytes: self.store.data().max_requested_memory_bytes,
             max_requested_table_elements: self.store.data().max_requested_table_elements,
             outbound_requests: self.store.data().http.requests.take(),
-        }
+            host_calls: std::mem::take(&mut self.store.data_mut().host_calls),
+            phases: self.take_phases(),
+            profile: self.take_profile(),
+        };
+        evaluation.redact(&self.secrets);
+        evaluation
     }
     pub async fn evaluate(
-        mut self,
+        &mut self,
         code: &str,
-        parameters: &[serde_json::Value],
+        parameters: &[SandboxValue],
         options: &EvaluateOptions,
     ) -> SandboxEvaluationResult {
         let parameters = match prepare_parameters(parameters) {
             Ok(params) => params,
-            Err(err) => {
-                return SandboxEvaluationResult {
-                    result: Err(err),
-                    stdout: String::new(),
-                    stderr: String::new(),
-                    fuel_remaining: self.store.get_fuel().unwrap_or(0),
-                    max_requested_memory_bytes: self.store.data().max_requested_memory_bytes,
-                    max_requested_table_elements: self.store.data().max_requested_table_elements,
-                    outbound_requests: self.store.data().http.requests.take(),
-                };
-            }
+            Err(err) => return self.handle_error(err),
         };
+        let sandbox_options = options.sandbox_options();
         let result = match &options.mode {
             EvaluateMode::FunctionCall => {
-                self.sandbox
-                    .call_evaluate(
+                run_guest(
+                    self.deadline,
+                    &self.cancel,
+                    self.sandbox.call_evaluate(
                         &mut self.store,
                         code,
                         &parameters,
-                        &bindings::SandboxOptions {
-                            strip_types: options.strip_typescript_types,
-                            filename: options.filename.clone(),
-                        },
-                    )
-                    .await
+                        &sandbox_options,
+                    ),
+                )
+                .await
             }
             EvaluateMode::ModuleMethod(method) => {
-                self.sandbox
-                    .call_evaluate_module(
+                run_guest(
+                    self.deadline,
+                    &self.cancel,
+                    self.sandbox.call_evaluate_module(
                         &mut self.store,
                         code,
                         method,
                         &parameters,
-                        &bindings::SandboxOptions {
-                            strip_types: options.strip_typescript_types,
-                            filename: options.filename.clone(),
-                        },
-                    )
-                    .await
+                        &sandbox_options,
+                    ),
+                )
+                .await
             }
         };
-        self.handle_result(result)
+        self.handle_result(result, &options.result_limits)
+    }
+    async fn parse(
+        &mut self,
+        code: &str,
+        options: &EvaluateOptions,
+    ) -> Result<(bindings::PreparedScript, Vec<OutboundRequest>), EvaluateError> {
+        let result = run_guest(
+            self.deadline,
+            &self.cancel,
+            self.sandbox.call_prepare(
+                &mut self.store,
+                code,
+                matches!(options.mode, EvaluateMode::ModuleMethod(_)),
+                &options.sandbox_options(),
+            ),
+        )
+        .await;
+        let script = match result {
+            Ok(script) => script.map_err(|error| {
+                let mut error: JavaScriptError = error.into();
+                self.secrets.redact_error(&mut error);
+                EvaluateError::JavaScriptError(error)
+            })?,
+            Err(err) => return Err(self.trap_error(err)),
+        };
+        let mut outbound_requests = self.store.data().http.requests.take();
+        for request in &mut outbound_requests {
+            request.0 = self.secrets.redact_uri(&request.0);
+        }
+        Ok((script, outbound_requests))
+    }
+    async fn evaluate_prepared(
+        &mut self,
+        script: &bindings::PreparedScript,
+        parameters: &[SandboxValue],
+        options: &EvaluateOptions,
+    ) -> SandboxEvaluationResult {
+        let parameters = match prepare_parameters(parameters) {
+            Ok(params) => params,
+            Err(err) => return self.handle_error(err),
+        };
+        let result = run_guest(
+            self.deadline,
+            &self.cancel,
+            self.sandbox.call_evaluate_prepared(
+                &mut self.store,
+                script,
+                options.method(),
+                &parameters,
+                &options.sandbox_options(),
+            ),
+        )
+        .await;
+        self.handle_result(result, &options.result_limits)
+    }
+    async fn load_batch(
+        &mut self,
+        code: &str,
+        is_module: bool,
+        options: &EvaluateOptions,
+    ) -> SandboxEvaluationResult {
+        let result = run_guest(
+            self.deadline,
+            &self.cancel,
+            self.sandbox.call_load_batch(
+                &mut self.store,
+                code,
+                is_module,
+                &options.sandbox_options(),
+            ),
+        )
+        .await;
+        let result = result.map(|loaded| loaded.map(|()| Value::Json("null".to_string())));
+        self.handle_result(result, &options.result_limits)
+    }
+    async fn evaluate_batch_item(
+        &mut self,
+        call: &BatchCall,
+        options: &EvaluateOptions,
+    ) -> SandboxEvaluationResult {
+        let parameters = match prepare_parameters(&call.parameters) {
+            Ok(params) => params,
+            Err(err) => return self.handle_error(err),
+        };
+        let result = run_guest(
+            self.deadline,
+            &self.cancel,
+            self.sandbox.call_evaluate_batch_item(
+                &mut self.store,
+                call.method.as_deref(),
+                &parameters,
+                &options.sandbox_options(),
+            ),
+        )
+        .await;
+        self.handle_result(result, &options.result_limits)
     }
 }
 
+/// Epoch interruption only stops the guest while it is executing, so this
+/// also stops waiting if the guest is blocked on a timer or outbound request.
+async fn run_guest<T>(
+    deadline: Option<Instant>,
+    cancel: &CancellationToken,
+    call: impl Future<Output = wasmtime::Result<T>>,
+) -> wasmtime::Result<T> {
+    let call = async {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), call)
+                .await
+                .unwrap_or_else(|_| Err(Trap::Interrupt.into())),
+            None => call.await,
+        }
+    };
+    tokio::select! {
+        result = call => result,
+        () = cancel.cancelled() => Err(Trap::Interrupt.into()),
+    }
+}
+
+fn is_interrupt(err: &wasmtime::Error) -> bool {
+    matches!(err.downcast_ref::<Trap>(), Some(Trap::Interrupt))
+}
+
 pub struct SandboxEvaluationResult {
-    pub result: Result<serde_json::Value, EvaluateError>,
+    pub result: Result<SandboxValue, EvaluateError>,
     pub stdout: String,
     pub stderr: String,
+    /// Structured records of the calls to `console` methods.
+    pub logs: Vec<LogRecord>,
+    /// Whether any output was dropped because this call's output exceeded
+    /// `MemoryLimits::stdout_bytes` or `MemoryLimits::stderr_bytes`.
+    pub logs_truncated: bool,
+    /// The seed used for the random number generators, if the sandbox was
+    /// configured with `Determinism`.
+    pub seed: Option<u64>,
+    /// How far the clocks were fast-forwarded, if `virtual_time` was enabled.
+    pub virtual_time_elapsed: Option<Duration>,
     pub fuel_remaining: u64,
     pub max_requested_memory_bytes: Option<usize>,
     pub max_requested_table_elements: Option<usize>,
     pub outbound_requests: Vec<OutboundRequest>,
+    pub host_calls: Vec<HostCall>,
+    /// How the fuel and time were split between creating the sandbox,
+    /// compiling the code, loading imported modules and running the code.
+    pub phases: Vec<PhaseUsage>,
+    /// Samples of the guest's call stack in the Firefox Profiler's JSON
+    /// format, if `SandboxConfig::profile` was set. Load it at
+    /// <https://profiler.firefox.com>.
+    pub profile: Option<Vec<u8>>,
 }
-fn prepare_parameters(parameters: &[serde_json::Value]) -> Result<Vec<String>, EvaluateError> {
+impl SandboxEvaluationResult {
+    fn from_error(err: impl Into<EvaluateError>) -> Self {
+        SandboxEvaluationResult {
+            result: Err(err.into()),
+            stdout: String::new(),
+            stderr: String::new(),
+            logs: vec![],
+            logs_truncated: false,
+            seed: None,
+            virtual_time_elapsed: None,
+            fuel_remaining: 0,
+            max_requested_memory_bytes: None,
+            max_requested_table_elements: None,
+            outbound_requests: vec![],
+            host_calls: vec![],
+            phases: vec![],
+            profile: None,
+        }
+    }
+    /// Add the output and usage of loading the code again before this item,
+    /// under `BatchBudget::PerItem`. The fuel it used is taken from
+    /// `fuel_remaining`, as if the item had used it.
+    fn include_reload(&mut self, reload: SandboxEvaluationResult, cpu_fuel: u64) {
+        let reload_fuel = cpu_fuel.saturating_sub(reload.fuel_remaining);
+        self.fuel_remaining = self.fuel_remaining.saturating_sub(reload_fuel);
+        self.stdout.insert_str(0, &reload.stdout);
+        self.stderr.insert_str(0, &reload.stderr);
+        self.logs.splice(0..0, reload.logs);
+        self.logs_truncated |= reload.logs_truncated;
+        self.max_requested_memory_bytes = self
+            .max_requested_memory_bytes
+            .max(reload.max_requested_memory_bytes);
+        self.max_requested_table_elements = self
+            .max_requested_table_elements
+            .max(reload.max_requested_table_elements);
+        self.outbound_requests
+            .splice(0..0, reload.outbound_requests);
+        self.host_calls.splice(0..0, reload.host_calls);
+        self.phases.splice(0..0, reload.phases);
+    }
+    /// Remove secret values from everything the sandbox could have written
+    /// them to.
+    fn redact(&mut self, secrets: &Secrets) {
+        if secrets.is_empty() {
+            return;
+        }
+        self.stdout = secrets.redact(&self.stdout);
+        self.stderr = secrets.redact(&self.stderr);
+        for record in &mut self.logs {
+            record.message = secrets.redact(&record.message);
+        }
+        if let Err(EvaluateError::JavaScriptError(error)) = &mut self.result {
+            secrets.redact_error(error);
+        }
+        for request in &mut self.outbound_requests {
+            request.0 = secrets.redact_uri(&request.0);
+        }
+    }
+}
+/// Parse a value returned by the sandbox, once it's known to be within
+/// `limits`.
+pub(crate) fn decode_value(
+    value: Value,
+    limits: &ResultLimits,
+) -> Result<SandboxValue, EvaluateError> {
+    match value {
+        Value::Json(json) => limits
+            .check_json(&json)
+            .map_err(EvaluateError::ResultLimitExceeded)
+            .and_then(|()| serde_json::from_str(&json).map_err(Into::into))
+            .map(SandboxValue::Json),
+        Value::Bytes(bytes) => limits
+            .check_bytes(bytes.len())
+            .map(|()| SandboxValue::Bytes(bytes))
+            .map_err(EvaluateError::ResultLimitExceeded),
+    }
+}
+
+fn prepare_parameters(parameters: &[SandboxValue]) -> Result<Vec<Value>, EvaluateError> {
     let parameters: Vec<_> = parameters
         .iter()
-        .map(serde_json::to_string)
+        .map(|parameter| match parameter {
+            SandboxValue::Json(value) => serde_json::to_string(value).map(Value::Json),
+            SandboxValue::Bytes(bytes) => Ok(Value::Bytes(bytes.clone())),
+        })
         .collect::<Result<_, _>>()?;
     Ok(parameters)
 }
 
-#[allow(clippy::needless_pass_by_value)]
-fn take_memory_pipe_contents(pipe: MemoryOutputPipe) -> String {
-    std::str::from_utf8(&pipe.contents())
-        .map_or_else(|_| "<invalid utf8 output>".to_string(), ToOwned::to_owned)
-}
-
+/// Returns the output written since `offset`, and moves `offset` past it.
 // #[non_exhaustive]
 #[derive(Clone)]
 struct EvaluateOptions {
     pub mode: EvaluateMode,
     pub strip_typescript_types: bool,
     pub filename: Option<String>,
+    pub value_encoding: ValueEncoding,
+    pub result_limits: ResultLimits,
+    pub stream: bool,
 }
 
 impl Default for EvaluateOptions {
@@ -377,6 +1597,29 @@ impl Default for EvaluateOptions {
             mode: EvaluateMode::FunctionCall,
             strip_typescript_types: false,
             filename: None,
+            value_encoding: ValueEncoding::default(),
+            result_limits: ResultLimits::default(),
+            stream: false,
+        }
+    }
+}
+
+impl EvaluateOptions {
+    fn method(&self) -> Option<&str> {
+        match &self.mode {
+            EvaluateMode::ModuleMethod(method) => Some(method.as_ref()),
+            EvaluateMode::FunctionCall => None,
+        }
+    }
+    fn sandbox_options(&self) -> bindings::SandboxOptions {
+        bindings::SandboxOptions {
+            strip_types: self.strip_typescript_types,
+            filename: self.filename.clone(),
+            value_encoding: match self.value_encoding {
+                ValueEncoding::Json => bindings::ValueEncoding::Json,
+                ValueEncoding::Tagged => bindings::ValueEncoding::Tagged,
+            },
+            stream: self.stream,
         }
     }
 }
@@ -0,0 +1,347 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use hyper::HeaderMap;
+use hyper::header::HeaderValue;
+use serde::Deserialize;
+
+use crate::JavaScriptError;
+use crate::value::SandboxValue;
+
+/// Replaces secret values in the output returned from a sandbox.
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+const PLACEHOLDER_START: &str = "{{secret:";
+const PLACEHOLDER_END: &str = "}}";
+
+/// A secret value, and optionally the hosts it is bound to.
+#[derive(Clone, Deserialize)]
+#[serde(from = "SerializedSecret")]
+pub struct Secret {
+    pub value: String,
+    /// If this is set, JavaScript can't read the value. Instead, headers of
+    /// requests to these hosts can contain `{{secret:name}}` placeholders,
+    /// which are replaced with the value once the request has been allowed.
+    pub hosts: Option<HashSet<String>>,
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self { value, hosts: None }
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Secret")
+            .field("value", &REDACTED)
+            .field("hosts", &self.hosts)
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SerializedSecret {
+    Value(String),
+    Bound { value: String, hosts: Vec<String> },
+}
+
+impl From<SerializedSecret> for Secret {
+    fn from(secret: SerializedSecret) -> Self {
+        match secret {
+            SerializedSecret::Value(value) => Secret::from(value),
+            SerializedSecret::Bound { value, hosts } => Secret {
+                value,
+                hosts: Some(hosts.into_iter().collect()),
+            },
+        }
+    }
+}
+
+/// Named values that JavaScript can read from the `secrets` global, without
+/// passing them in as parameters, or use in outbound requests to the hosts
+/// they are bound to. The values are redacted from the output, errors and
+/// outbound request URIs in the `SandboxEvaluationResult`.
+#[derive(Clone, Default)]
+pub struct Secrets(Arc<SecretsInner>);
+
+#[derive(Default)]
+struct SecretsInner {
+    values: HashMap<String, Secret>,
+    /// Longest first, so a secret that contains another is redacted whole.
+    redact_order: Vec<String>,
+}
+
+impl Secrets {
+    #[must_use]
+    pub fn new<T: Into<Secret>>(values: HashMap<String, T>) -> Self {
+        let values: HashMap<String, Secret> = values
+            .into_iter()
+            .map(|(name, secret)| (name, secret.into()))
+            .collect();
+        let mut redact_order: Vec<String> = values
+            .values()
+            .filter(|secret| !secret.value.is_empty())
+            .map(|secret| secret.value.clone())
+            .collect();
+        redact_order.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        Self(Arc::new(SecretsInner {
+            values,
+            redact_order,
+        }))
+    }
+    /// Returns the value of a secret that JavaScript is allowed to read, i.e.
+    /// one that isn't bound to any hosts.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .values
+            .get(name)
+            .filter(|secret| secret.hosts.is_none())
+            .map(|secret| secret.value.as_str())
+    }
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.values.is_empty()
+    }
+    pub(crate) fn redact(&self, text: &str) -> String {
+        self.redact_with(text, REDACTED)
+    }
+    /// How much of `bytes` can be redacted and sent on now, when more output
+    /// may follow. The rest could be the start of a secret that the next
+    /// write completes, so it is at most as long as the longest secret.
+    pub(crate) fn redactable_prefix_len(&self, bytes: &[u8]) -> usize {
+        let Some(longest) = self.0.redact_order.first() else {
+            return bytes.len();
+        };
+        let mut cut = bytes.len().saturating_sub(longest.len() - 1);
+        // Any secret that starts before the cut ends within `bytes`, so move
+        // the cut past those that span it rather than splitting them.
+        loop {
+            let spanning_end = self
+                .0
+                .redact_order
+                .iter()
+                .filter_map(|value| {
+                    (cut.saturating_sub(value.len() - 1)..cut)
+                        .find(|&start| bytes[start..].starts_with(value.as_bytes()))
+                        .map(|start| start + value.len())
+                })
+                .max();
+            match spanning_end {
+                Some(end) => cut = end,
+                None => return cut,
+            }
+        }
+    }
+    /// Redact the strings and keys in a JSON value, or bytes that are valid
+    /// UTF-8.
+    pub(crate) fn redact_value(&self, value: &mut SandboxValue) {
+        if self.is_empty() {
+            return;
+        }
+        match value {
+            SandboxValue::Json(json) => self.redact_json(json),
+            SandboxValue::Bytes(bytes) => {
+                if let Ok(text) = std::str::from_utf8(bytes) {
+                    *bytes = self.redact(text).into_bytes();
+                }
+            }
+        }
+    }
+    fn redact_json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(text) => *text = self.redact(text),
+            serde_json::Value::Array(items) => {
+                for item in items {
+                    self.redact_json(item);
+                }
+            }
+            serde_json::Value::Object(map) => {
+                *map = std::mem::take(map)
+                    .into_iter()
+                    .map(|(key, mut item)| {
+                        self.redact_json(&mut item);
+                        (self.redact(&key), item)
+                    })
+                    .collect();
+            }
+            serde_json::Value::Null | serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+            }
+        }
+    }
+    /// `[` and `]` aren't allowed in most parts of a URI, so this uses a
+    /// replacement that keeps the URI valid.
+    pub(crate) fn redact_uri(&self, uri: &hyper::Uri) -> hyper::Uri {
+        let redacted = self.redact_with(&uri.to_string(), "REDACTED");
+        redacted.parse().unwrap_or_else(|_| uri.clone())
+    }
+    pub(crate) fn redact_error(&self, error: &mut JavaScriptError) {
+        error.message = self.redact(&error.message);
+        for frame in &mut error.stack {
+            frame.filename = self.redact(&frame.filename);
+        }
+        if let Some(value) = &error.value {
+            let redacted = self.redact(&value.to_string());
+            error.value = serde_json::from_str(&redacted).ok();
+        }
+        if let Some(cause) = &mut error.cause {
+            self.redact_error(cause);
+        }
+    }
+    /// Replace `{{secret:name}}` placeholders in `headers` with the values of
+    /// the secrets bound to `host`. Placeholders for other secrets are left
+    /// as they are.
+    pub(crate) fn substitute_headers(
+        &self,
+        host: &str,
+        headers: &mut HeaderMap<HeaderValue>,
+    ) -> Result<(), String> {
+        for value in headers.values_mut() {
+            let Ok(text) = value.to_str() else {
+                continue;
+            };
+            if !text.contains(PLACEHOLDER_START) {
+                continue;
+            }
+            let substituted = self.substitute(host, text);
+            *value = HeaderValue::from_str(&substituted)
+                .map_err(|_| "Secret is not a valid header value".to_string())?;
+            value.set_sensitive(true);
+        }
+        Ok(())
+    }
+    fn substitute(&self, host: &str, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find(PLACEHOLDER_START) {
+            let name_start = start + PLACEHOLDER_START.len();
+            let Some(name_len) = rest[name_start..].find(PLACEHOLDER_END) else {
+                break;
+            };
+            let name = &rest[name_start..name_start + name_len];
+            let end = name_start + name_len + PLACEHOLDER_END.len();
+            result.push_str(&rest[..start]);
+            match self.0.values.get(name) {
+                Some(Secret {
+                    value,
+                    hosts: Some(hosts),
+                }) if hosts.contains(host) => result.push_str(value),
+                _ => result.push_str(&rest[start..end]),
+            }
+            rest = &rest[end..];
+        }
+        result.push_str(rest);
+        result
+    }
+    fn redact_with(&self, text: &str, replacement: &str) -> String {
+        let mut text = text.to_string();
+        for value in &self.0.redact_order {
+            if text.contains(value.as_str()) {
+                text = text.replace(value.as_str(), replacement);
+            }
+        }
+        text
+    }
+}
+
+impl<T: Into<Secret>> From<HashMap<String, T>> for Secrets {
+    fn from(values: HashMap<String, T>) -> Self {
+        Self::new(values)
+    }
+}
+
+impl fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<_> = self.0.values.keys().collect();
+        names.sort();
+        f.debug_tuple("Secrets").field(&names).finish()
+    }
+}
+
+#[test]
+fn test_redact() {
+    let secrets = Secrets::new(HashMap::from([
+        ("short".to_string(), "abc".to_string()),
+        ("long".to_string(), "abcdef".to_string()),
+        ("empty".to_string(), String::new()),
+    ]));
+    assert_eq!(
+        secrets.redact("key=abcdef, other=abc"),
+        "key=[REDACTED], other=[REDACTED]"
+    );
+    assert_eq!(
+        secrets
+            .redact_uri(&"https://example.com/?key=abcdef".parse().unwrap())
+            .to_string(),
+        "https://example.com/?key=REDACTED"
+    );
+    assert_eq!(
+        format!("{secrets:?}"),
+        r#"Secrets(["empty", "long", "short"])"#
+    );
+}
+
+#[test]
+fn test_redact_value() {
+    let secrets = Secrets::new(HashMap::from([("key".to_string(), "ab\"c".to_string())]));
+    let mut value = SandboxValue::Json(serde_json::json!({ "ab\"c": ["x ab\"c", 1] }));
+    secrets.redact_value(&mut value);
+    assert_eq!(
+        value,
+        SandboxValue::Json(serde_json::json!({ "[REDACTED]": ["x [REDACTED]", 1] }))
+    );
+    let mut value = SandboxValue::Bytes(b"ab\"c!".to_vec());
+    secrets.redact_value(&mut value);
+    assert_eq!(value, SandboxValue::Bytes(b"[REDACTED]!".to_vec()));
+}
+
+#[test]
+fn test_redactable_prefix_len() {
+    let secrets = Secrets::new(HashMap::from([
+        ("short".to_string(), "abc".to_string()),
+        ("long".to_string(), "abcdef".to_string()),
+    ]));
+    // "abcde" might become "abcdef", so it is held back.
+    assert_eq!(secrets.redactable_prefix_len(b"xxabcde"), 2);
+    // A secret that spans the cut is sent whole.
+    assert_eq!(secrets.redactable_prefix_len(b"xabcxxx"), 4);
+    assert_eq!(secrets.redactable_prefix_len(b"abc"), 0);
+    assert_eq!(Secrets::default().redactable_prefix_len(b"abc"), 3);
+}
+
+#[test]
+fn test_substitute_headers() {
+    let secrets = Secrets::new(HashMap::from([
+        (
+            "github".to_string(),
+            Secret {
+                value: "ghp_123".to_string(),
+                hosts: Some(HashSet::from(["api.github.com".to_string()])),
+            },
+        ),
+        ("readable".to_string(), Secret::from("abc".to_string())),
+    ]));
+    assert_eq!(secrets.get("github"), None);
+    assert_eq!(secrets.get("readable"), Some("abc"));
+
+    let placeholders = "Bearer {{secret:github}} {{secret:readable}} {{secret:";
+    let mut headers = HeaderMap::new();
+    headers.insert("authorization", HeaderValue::from_static(placeholders));
+    secrets
+        .substitute_headers("api.github.com", &mut headers)
+        .unwrap();
+    assert_eq!(
+        headers["authorization"],
+        "Bearer ghp_123 {{secret:readable}} {{secret:"
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert("authorization", HeaderValue::from_static(placeholders));
+    secrets
+        .substitute_headers("example.com", &mut headers)
+        .unwrap();
+    assert_eq!(headers["authorization"], placeholders);
+}
@@ -1,5 +1,13 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use http_body_util::BodyExt;
+use tokio_util::sync::CancellationToken;
+
 use wasmtime::ResourceLimiter;
-use wasmtime::component::HasData;
+use wasmtime::component::{HasData, Resource};
+use wasmtime_wasi::p2::DynPollable;
+use wasmtime_wasi::p2::bindings::clocks::monotonic_clock;
 use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxView, WasiView};
 use wasmtime_wasi_http::p2::bindings::http::types::ErrorCode;
 use wasmtime_wasi_http::p2::types::HostFutureIncomingResponse;
@@ -8,9 +16,18 @@ use wasmtime_wasi_http::{
     p2::{WasiHttpCtxView, WasiHttpHooks, WasiHttpView},
 };
 
+use crate::clocks::{MonotonicClock, PendingIoBody, VirtualTime};
+use crate::host_functions::{HostCall, HostFunctionFuture, HostFunctions};
 use crate::http::{OutboundRequest, send_request_handler};
+use crate::kv::KvState;
 use crate::memory::MemoryLimits;
+use crate::output::{LogLevel, LogRecorder};
+use crate::phases::PhaseRecorder;
+use crate::profile::Profiler;
+use crate::sandbox::decode_value;
+use crate::secrets::Secrets;
 use crate::shared_vec::SharedVec;
+use crate::stream::StreamState;
 use crate::{
     CustomHttpMode, CustomImportMap, RequestLimit, RequestValidationOutcome, ResolvedModule,
 };
@@ -22,6 +39,14 @@ pub(crate) struct SandboxState<TImportMap: CustomImportMap, THttpMode: CustomHtt
     pub wasi_http: WasiHttpCtx,
     pub http: SandboxHttpState<THttpMode>,
     pub imports: TImportMap,
+    pub logs: LogRecorder,
+    pub monotonic_clock: MonotonicClock,
+    pub host_functions: Option<Arc<dyn HostFunctions>>,
+    pub host_calls: Vec<HostCall>,
+    pub kv: Option<KvState>,
+    pub stream: Option<StreamState>,
+    pub profiler: Option<Profiler>,
+    pub phases: PhaseRecorder,
     pub max_requested_memory_bytes: Option<usize>,
     pub max_requested_table_elements: Option<usize>,
 }
@@ -53,6 +78,14 @@ pub(crate) struct SandboxHttpState<THttpMode: CustomHttpMode> {
     pub requests: SharedVec<OutboundRequest>,
     pub request_limit: RequestLimit,
     pub http: THttpMode,
+    /// Outbound requests still running at this point are aborted.
+    pub deadline: Option<Instant>,
+    /// Outbound requests still running when this is cancelled are aborted.
+    pub cancel: CancellationToken,
+    /// Virtual time stands still while outbound requests are in flight.
+    pub virtual_time: Option<VirtualTime>,
+    /// Substituted into the headers of outbound requests.
+    pub secrets: Secrets,
 }
 impl<THttpMode: CustomHttpMode> WasiHttpHooks for SandboxHttpState<THttpMode> {
     fn send_request(
@@ -74,8 +107,33 @@ impl<THttpMode: CustomHttpMode> WasiHttpHooks for SandboxHttpState<THttpMode> {
         }
         let http_mode = self.http.clone();
         let requests = self.requests.clone();
+        let deadline = self.deadline;
+        let cancel = self.cancel.clone();
+        let virtual_time = self.virtual_time.clone();
+        let pending_io = virtual_time.as_ref().map(VirtualTime::start_io);
+        let secrets = self.secrets.clone();
         let handle = wasmtime_wasi::runtime::spawn(async move {
-            let result = send_request_handler(request, config, &http_mode, requests).await;
+            let response = async {
+                let response =
+                    send_request_handler(request, config, &http_mode, requests, &secrets);
+                match deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline.into(), response)
+                        .await
+                        .unwrap_or(Err(ErrorCode::ConnectionTimeout)),
+                    None => response.await,
+                }
+            };
+            let mut result = tokio::select! {
+                result = response => result,
+                () = cancel.cancelled() => Err(ErrorCode::ConnectionTerminated),
+            };
+            // Once the headers are here, time only stands still while the
+            // guest is waiting to read from the body.
+            drop(pending_io);
+            if let (Ok(response), Some(virtual_time)) = (&mut result, virtual_time) {
+                let body = std::mem::take(response.resp.body_mut());
+                *response.resp.body_mut() = PendingIoBody::new(body, virtual_time).boxed_unsync();
+            }
             Ok(result)
         });
         Ok(HostFutureIncomingResponse::pending(handle))
@@ -186,6 +244,98 @@ impl<TImportMap: CustomImportMap, THttpMode: CustomHttpMode> crate::sandbox::Hos
     async fn load_import(&mut self, id: String) -> Result<String, String> {
         self.imports.load_import(id).map_err(|e| e.to_string())
     }
+    async fn log(
+        &mut self,
+        level: crate::sandbox::LogLevel,
+        message: String,
+        stack: Option<String>,
+    ) {
+        let level = match level {
+            crate::sandbox::LogLevel::Debug => LogLevel::Debug,
+            crate::sandbox::LogLevel::Info => LogLevel::Info,
+            crate::sandbox::LogLevel::Warn => LogLevel::Warn,
+            crate::sandbox::LogLevel::Error => LogLevel::Error,
+        };
+        self.logs.push(level, message, stack.as_deref());
+    }
+    async fn call_host(&mut self, name: String, args: String) -> Result<String, String> {
+        let start = Instant::now();
+        let result = match self.start_host_call(&name, &args) {
+            Ok(call) => call.await.map_err(|err| err.to_string()),
+            Err(err) => Err(err),
+        };
+        self.host_calls.push(HostCall {
+            name,
+            success: result.is_ok(),
+            duration: start.elapsed(),
+        });
+        serde_json::to_string(&result?).map_err(|err| err.to_string())
+    }
+    async fn kv_get(&mut self, key: String) -> Result<Option<String>, String> {
+        self.kv()?.get(&key).await
+    }
+    async fn kv_set(&mut self, key: String, value: String) -> Result<(), String> {
+        self.kv()?.set(&key, value).await
+    }
+    async fn kv_delete(&mut self, key: String) -> Result<bool, String> {
+        self.kv()?.delete(&key).await
+    }
+    async fn kv_list(&mut self, prefix: String) -> Result<Vec<String>, String> {
+        self.kv()?.list(&prefix).await
+    }
+    async fn get_secret(&mut self, name: String) -> Option<String> {
+        self.http.secrets.get(&name).map(str::to_string)
+    }
+    async fn stream_item(&mut self, item: crate::sandbox::Value) -> Result<(), String> {
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| "Streaming is not enabled".to_string())?;
+        let mut item = decode_value(item, stream.result_limits()).map_err(|err| err.to_string())?;
+        self.http.secrets.redact_value(&mut item);
+        stream.send(item).await
+    }
+}
+
+impl<TImportMap: CustomImportMap, THttpMode: CustomHttpMode> SandboxState<TImportMap, THttpMode> {
+    fn kv(&mut self) -> Result<&mut KvState, String> {
+        self.kv
+            .as_mut()
+            .ok_or_else(|| "KV storage is not enabled".to_string())
+    }
+    fn start_host_call(&self, name: &str, args: &str) -> Result<HostFunctionFuture, String> {
+        let args = serde_json::from_str(args).map_err(|err| err.to_string())?;
+        self.host_functions
+            .as_ref()
+            .and_then(|functions| functions.call(name, args))
+            .ok_or_else(|| format!("Unknown host function: {name}"))
+    }
+}
+
+impl<TImportMap: CustomImportMap, THttpMode: CustomHttpMode> monotonic_clock::Host
+    for SandboxState<TImportMap, THttpMode>
+{
+    fn now(&mut self) -> wasmtime::Result<monotonic_clock::Instant> {
+        Ok(self.monotonic_clock.now())
+    }
+    fn resolution(&mut self) -> wasmtime::Result<monotonic_clock::Duration> {
+        Ok(self.monotonic_clock.resolution())
+    }
+    fn subscribe_instant(
+        &mut self,
+        when: monotonic_clock::Instant,
+    ) -> wasmtime::Result<Resource<DynPollable>> {
+        self.monotonic_clock
+            .subscribe_instant(&mut self.resource_table, when)
+    }
+    fn subscribe_duration(
+        &mut self,
+        duration: monotonic_clock::Duration,
+    ) -> wasmtime::Result<Resource<DynPollable>> {
+        let when = self.monotonic_clock.now().saturating_add(duration);
+        self.monotonic_clock
+            .subscribe_instant(&mut self.resource_table, when)
+    }
 }
 
 // {
@@ -0,0 +1,92 @@
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::ResourceLimit;
+use crate::value::{ResultLimits, SandboxValue};
+
+/// Receives the values yielded by an async iterator that the sandboxed
+/// function returned, with `SandboxConfig::secrets` redacted. The sandbox waits while the channel is full, so its
+/// capacity controls how far the sandbox can get ahead of the receiver.
+pub type ItemSink = mpsc::Sender<SandboxValue>;
+
+/// Streams the values yielded by an async iterator, such as an
+/// `async function*`, to an `ItemSink` as they are produced. The result of the
+/// evaluation is then the iterator's return value.
+#[derive(Clone)]
+pub struct StreamConfig {
+    pub sink: ItemSink,
+    pub limits: StreamLimits,
+}
+
+impl StreamConfig {
+    /// Create a `StreamConfig` that sends to a channel with a capacity of
+    /// `limits.buffer_items`.
+    #[must_use]
+    pub fn channel(limits: StreamLimits) -> (Self, mpsc::Receiver<SandboxValue>) {
+        let (sink, receiver) = mpsc::channel(usize::from(limits.buffer_items).max(1));
+        (Self { sink, limits }, receiver)
+    }
+}
+
+/// These apply to each evaluation, or to a session as a whole.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct StreamLimits {
+    /// The maximum number of items that can be yielded.
+    #[serde(default = "default_max_items")]
+    pub max_items: ResourceLimit,
+    /// How many items can be waiting for the receiver before the sandbox
+    /// is paused. Only used by `StreamConfig::channel`.
+    #[serde(default = "default_buffer_items")]
+    pub buffer_items: ResourceLimit,
+}
+impl Default for StreamLimits {
+    fn default() -> Self {
+        Self {
+            max_items: default_max_items(),
+            buffer_items: default_buffer_items(),
+        }
+    }
+}
+
+fn default_max_items() -> ResourceLimit {
+    ResourceLimit(10_000)
+}
+fn default_buffer_items() -> ResourceLimit {
+    ResourceLimit(16)
+}
+
+/// Tracks the items a sandbox has sent to its `StreamConfig`.
+pub(crate) struct StreamState {
+    config: StreamConfig,
+    result_limits: ResultLimits,
+    items: usize,
+}
+
+impl StreamState {
+    pub fn new(config: StreamConfig, result_limits: ResultLimits) -> Self {
+        Self {
+            config,
+            result_limits,
+            items: 0,
+        }
+    }
+    /// Waits until the sink has room for the item, so a slow receiver pauses
+    /// the sandbox rather than buffering without limit.
+    pub async fn send(&mut self, item: SandboxValue) -> Result<(), String> {
+        self.items = self.items.saturating_add(1);
+        let max_items = usize::from(self.config.limits.max_items);
+        if self.items > max_items {
+            return Err(format!(
+                "Stream is longer than the limit of {max_items} items"
+            ));
+        }
+        self.config
+            .sink
+            .send(item)
+            .await
+            .map_err(|_| "Stream receiver was dropped".to_string())
+    }
+    pub fn result_limits(&self) -> &ResultLimits {
+        &self.result_limits
+    }
+}
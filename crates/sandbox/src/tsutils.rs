@@ -1,12 +1,17 @@
 use serde::Deserialize;
 use std::fmt;
+use tokio_util::sync::CancellationToken;
 use wasmtime::component::{Component, Linker};
 use wasmtime::{Config, Engine, Store};
 use wasmtime_wasi::{ResourceTable, WasiCtx};
 use wasmtime_wasi_http::WasiHttpCtx;
 
+use crate::clocks::MonotonicClock;
 use crate::http::BlockAllHttp;
 use crate::imports::ImportMapBlockAll;
+use crate::output::{LogRecorder, OutputKind, TeeOutputPipe};
+use crate::phases::PhaseRecorder;
+use crate::secrets::Secrets;
 use crate::shared_vec::SharedVec;
 use crate::state::{SandboxHttpState, SandboxState};
 use crate::{CpuFuel, MemoryLimits, RequestLimit};
@@ -98,6 +103,20 @@ impl TsUtilsEngine {
         config: TsUtilsSandboxConfig,
     ) -> wasmtime::Result<TsUtilsSandboxInstance> {
         let ctx: WasiCtx = WasiCtx::builder().inherit_stderr().inherit_stdout().build();
+        let logs = LogRecorder::new(
+            TeeOutputPipe::new(
+                config.memory_limits.stdout_bytes.into(),
+                OutputKind::Stdout,
+                None,
+                Secrets::default(),
+            ),
+            TeeOutputPipe::new(
+                config.memory_limits.stderr_bytes.into(),
+                OutputKind::Stderr,
+                None,
+                Secrets::default(),
+            ),
+        );
         let mut store = Store::new(
             &self.engine,
             SandboxState {
@@ -110,8 +129,20 @@ impl TsUtilsEngine {
                     request_limit: RequestLimit::Limited(0),
                     requests: SharedVec::default(),
                     request_count: 0,
+                    deadline: None,
+                    cancel: CancellationToken::new(),
+                    virtual_time: None,
+                    secrets: Secrets::default(),
                 },
                 imports: ImportMapBlockAll,
+                logs,
+                monotonic_clock: MonotonicClock::real(),
+                host_functions: None,
+                host_calls: vec![],
+                kv: None,
+                stream: None,
+                profiler: None,
+                phases: PhaseRecorder::new(0),
                 max_requested_memory_bytes: None,
                 max_requested_table_elements: None,
             },
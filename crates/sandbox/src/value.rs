@@ -0,0 +1,191 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::{MemorySizeBytes, ResourceLimit};
+
+/// A parameter passed to the sandbox, or the value it returned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SandboxValue {
+    /// Passed to JavaScript with `JSON.parse`, and returned using
+    /// `JSON.stringify`.
+    Json(serde_json::Value),
+    /// A `Uint8Array` in JavaScript. Returning an `ArrayBuffer` or a
+    /// `Uint8Array` gives a `Bytes` result. This only applies to the value
+    /// itself, not to byte arrays nested inside other values.
+    Bytes(Vec<u8>),
+}
+
+impl SandboxValue {
+    #[must_use]
+    pub fn as_json(&self) -> Option<&serde_json::Value> {
+        match self {
+            SandboxValue::Json(value) => Some(value),
+            SandboxValue::Bytes(_) => None,
+        }
+    }
+    #[must_use]
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            SandboxValue::Json(_) => None,
+            SandboxValue::Bytes(bytes) => Some(bytes),
+        }
+    }
+}
+
+impl From<serde_json::Value> for SandboxValue {
+    fn from(value: serde_json::Value) -> Self {
+        SandboxValue::Json(value)
+    }
+}
+
+impl From<Vec<u8>> for SandboxValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        SandboxValue::Bytes(bytes)
+    }
+}
+
+impl PartialEq<serde_json::Value> for SandboxValue {
+    fn eq(&self, other: &serde_json::Value) -> bool {
+        self.as_json() == Some(other)
+    }
+}
+
+/// How JSON parameters and results represent JavaScript values.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ValueEncoding {
+    /// Plain `JSON.stringify` and `JSON.parse`, so `undefined` and non-finite
+    /// numbers become `null`, `Map` and `Set` become `{}`, and `BigInt`
+    /// throws.
+    #[default]
+    Json,
+    /// Values that JSON can't represent are encoded as objects with a `$type`
+    /// key, e.g. `{"$type": "bigint", "value": "10"}`, so they are decoded to
+    /// the same values. Objects that have their own `$type` key are wrapped
+    /// in `{"$type": "object", "value": ...}`.
+    Tagged,
+}
+
+impl FromStr for ValueEncoding {
+    type Err = ();
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "JSON" => Ok(ValueEncoding::Json),
+            "TAGGED" => Ok(ValueEncoding::Tagged),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Limits on the value returned by the sandbox, which are checked before it
+/// is parsed.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct ResultLimits {
+    /// The maximum size of the JSON encoded result, or of a byte array.
+    #[serde(default)]
+    pub max_bytes: MemorySizeBytes,
+    /// The maximum nesting depth of arrays and objects. `serde_json` can't
+    /// parse values nested more than 128 levels deep in any case.
+    #[serde(default = "default_max_depth")]
+    pub max_depth: ResourceLimit,
+}
+impl Default for ResultLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: MemorySizeBytes::default(),
+            max_depth: default_max_depth(),
+        }
+    }
+}
+
+fn default_max_depth() -> ResourceLimit {
+    ResourceLimit(64)
+}
+
+/// The limit that a result exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultLimit {
+    Bytes(usize),
+    Depth(usize),
+}
+
+impl fmt::Display for ResultLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResultLimit::Bytes(limit) => {
+                write!(f, "Result is larger than the limit of {limit} bytes")
+            }
+            ResultLimit::Depth(limit) => {
+                write!(f, "Result is nested more than {limit} levels deep")
+            }
+        }
+    }
+}
+
+impl ResultLimits {
+    pub(crate) fn check_bytes(&self, len: usize) -> Result<(), ResultLimit> {
+        let limit = usize::from(self.max_bytes);
+        if len > limit {
+            return Err(ResultLimit::Bytes(limit));
+        }
+        Ok(())
+    }
+    pub(crate) fn check_json(&self, json: &str) -> Result<(), ResultLimit> {
+        self.check_bytes(json.len())?;
+        let limit = usize::from(self.max_depth);
+        if json_depth_exceeds(json, limit) {
+            return Err(ResultLimit::Depth(limit));
+        }
+        Ok(())
+    }
+}
+
+/// Whether arrays and objects in `json` are nested more than `limit` levels
+/// deep. This doesn't validate the JSON, which is left to the parser.
+fn json_depth_exceeds(json: &str, limit: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for byte in json.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > limit {
+                    return true;
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+#[test]
+fn test_result_limits() {
+    let limits = ResultLimits {
+        max_bytes: MemorySizeBytes(20),
+        max_depth: ResourceLimit(2),
+    };
+    assert_eq!(limits.check_json(r#"[{"a": "[[["}]"#), Ok(()));
+    assert_eq!(
+        limits.check_json(r#"[{"a": [1]}]"#),
+        Err(ResultLimit::Depth(2))
+    );
+    assert_eq!(
+        limits.check_json(r#""a long string value""#),
+        Err(ResultLimit::Bytes(20))
+    );
+}
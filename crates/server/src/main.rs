@@ -1,11 +1,17 @@
 #![deny(warnings, clippy::all, clippy::pedantic, clippy::unwrap_used)]
 #![allow(clippy::missing_errors_doc, clippy::unused_async_trait_impl)]
 
+use std::sync::Arc;
+
 use axum::{Router, routing::get};
 use secure_js_sandbox_axum_handler::{
-    AllowRequestToConfigureSandbox, SandboxServerConfig, TsUtilsHandler, create_evaluate_handler,
-    create_strip_types_handler, create_validate_module_handler, get_env,
+    AllowRequestToConfigureSandbox, CustomSandboxServerConfig, SandboxEngine, SandboxServerConfig,
+    TsUtilsHandler, create_evaluate_batch_handler, create_evaluate_handler,
+    create_evaluate_module_calls_handler, create_evaluate_ndjson_handler,
+    create_evaluate_stream_handler, create_sessions_router, create_strip_types_handler,
+    create_validate_module_handler, get_env,
 };
+use serde::de::DeserializeOwned;
 
 mod signal;
 
@@ -14,6 +20,7 @@ pub async fn main() -> anyhow::Result<()> {
     rustls::crypto::aws_lc_rs::default_provider()
         .install_default()
         .expect("Failed to install default TLS provider");
+    tracing_subscriber::fmt::init();
 
     let main = start_server();
     let signal_listener = signal::listen_signal();
@@ -29,15 +36,17 @@ pub async fn start_server() -> anyhow::Result<()> {
         // `GET /` goes to `root`
         .route("/", get(root));
     if get_env("SANDBOX_ALLOW_CONFIG_IN_REQUEST")?.unwrap_or(false) {
-        app = app.route(
-            "/evaluate",
-            create_evaluate_handler(AllowRequestToConfigureSandbox::from_env()?).await?,
-        );
+        app = add_evaluate_routes(app, AllowRequestToConfigureSandbox::from_env()?).await?;
     } else {
-        app = app.route(
-            "/evaluate",
-            create_evaluate_handler(SandboxServerConfig::from_env()?).await?,
-        );
+        let mut config = SandboxServerConfig::from_env()?;
+        if let Some(calibration) = config.calibrate_cpu_fuel_from_env().await? {
+            tracing::info!(
+                "SANDBOX_CPU_TIME is {} fuel on this machine ({} fuel per second)",
+                config.cpu_fuel.0,
+                calibration.fuel_per_second
+            );
+        }
+        app = add_evaluate_routes(app, config).await?;
     }
 
     let enable_strip_types_endpoint =
@@ -71,6 +80,51 @@ pub async fn start_server() -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn add_evaluate_routes<
+    TRequest: DeserializeOwned + Send + 'static,
+    TConfig: CustomSandboxServerConfig<TRequest>,
+>(
+    mut app: Router,
+    config: TConfig,
+) -> anyhow::Result<Router> {
+    // One engine for all the routes, so they share its compute threads and
+    // epoch ticker.
+    let engine = Arc::new(SandboxEngine::with_config(&config.get_engine_config())?);
+    let config = Arc::new(config);
+    app = app.route(
+        "/evaluate",
+        create_evaluate_handler(config.clone(), engine.clone()).await?,
+    );
+    if get_env("SANDBOX_ENABLE_EVALUATE_STREAM")?.unwrap_or(false) {
+        app = app.route(
+            "/evaluate/stream",
+            create_evaluate_stream_handler(config.clone(), engine.clone()).await?,
+        );
+    }
+    if get_env("SANDBOX_ENABLE_EVALUATE_NDJSON")?.unwrap_or(false) {
+        app = app.route(
+            "/evaluate/ndjson",
+            create_evaluate_ndjson_handler(config.clone(), engine.clone()).await?,
+        );
+    }
+    if get_env("SANDBOX_ENABLE_EVALUATE_BATCH")?.unwrap_or(false) {
+        app = app.route(
+            "/evaluate_batch",
+            create_evaluate_batch_handler(config.clone(), engine.clone()).await?,
+        );
+    }
+    if get_env("SANDBOX_ENABLE_EVALUATE_MODULE_CALLS")?.unwrap_or(false) {
+        app = app.route(
+            "/evaluate_module_calls",
+            create_evaluate_module_calls_handler(config.clone(), engine.clone()).await?,
+        );
+    }
+    if get_env("SANDBOX_ENABLE_SESSIONS")?.unwrap_or(false) {
+        app = app.merge(create_sessions_router(config, engine).await?);
+    }
+    Ok(app)
+}
+
 // basic handler that responds with a static string
 async fn root() -> &'static str {
     "Usage: POST /evaluate with JSON body"
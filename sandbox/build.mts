@@ -219,4 +219,18 @@ writeFileSync(
   readFileSync(join(import.meta.dirname, `wit/deps/host.wit`)),
 );
 
+// The crate's build script compares these with the sources, to catch a
+// guest that wasn't rebuilt after they changed.
+mkdirSync(join(rustSandboxDir, "sources"), { recursive: true });
+for (const [source, copy] of [
+  [`sandbox-host-code.js`, `sandbox-host-code.js`],
+  [`wit/sandbox.wit`, `sandbox.wit`],
+  [`wit/deps/host.wit`, `host.wit`],
+]) {
+  writeFileSync(
+    join(rustSandboxDir, "sources", copy),
+    readFileSync(join(import.meta.dirname, source)),
+  );
+}
+
 console.log("DONE");
@@ -3,23 +3,345 @@ import {
   stripTypesAndCompileModule,
   compileModule,
 } from "local:ts-utils/ts-utils-impl";
-import { resolveImportPath, loadImport } from "local:host/host-impl";
+import {
+  resolveImportPath,
+  loadImport,
+  log,
+  callHost,
+  kvGet,
+  kvSet,
+  kvDelete,
+  kvList,
+  getSecret,
+  streamItem,
+} from "local:host/host-impl";
+import { enterPhase, finishFetch, startFetch } from "local:host/phases";
+
+// Each console call is recorded and written to stdout or stderr by the
+// host, so the record and its output are kept together.
+const consoleLevels = { debug: "debug", log: "info", info: "info", warn: "warn", error: "error" };
+for (const [method, level] of Object.entries(consoleLevels)) {
+  console[method] = (...args) => {
+    log(level, args.map(formatLogArg).join(" "), new Error().stack);
+  };
+}
 
-async function output(fn) {
+// Functions registered by the embedder, which can be called as
+// `host.name(...args)` or imported from the `sandbox:host` module.
+const HOST_MODULE = "sandbox:host";
+const host = new Proxy(
+  {},
+  {
+    // `then` is excluded so that the module isn't mistaken for a promise.
+    get: (_, name) => (typeof name === "string" && name !== "then" ? hostFunction(name) : undefined),
+    has: (_, name) => typeof name === "string" && name !== "then",
+  },
+);
+globalThis.host = host;
+
+function hostFunction(name) {
+  return async (...args) => JSON.parse(callImport(() => callHost(name, JSON.stringify(args))));
+}
+
+// Key-value storage, if the embedder has enabled it. Values are stored as
+// JSON.
+globalThis.kv = {
+  async get(key) {
+    const value = callImport(() => kvGet(`${key}`));
+    return value === undefined ? undefined : JSON.parse(value);
+  },
+  async set(key, value) {
+    callImport(() => kvSet(`${key}`, JSON.stringify(value) ?? "null"));
+  },
+  async delete(key) {
+    return callImport(() => kvDelete(`${key}`));
+  },
+  async list(prefix = "") {
+    return callImport(() => kvList(`${prefix}`));
+  },
+};
+
+// Secrets configured by the embedder, as read-only properties. Their values
+// are redacted from the output before it's returned.
+globalThis.secrets = new Proxy(
+  {},
+  {
+    get: (_, name) => (typeof name === "string" ? getSecret(name) : undefined),
+    has: (_, name) => typeof name === "string" && getSecret(name) !== undefined,
+    set: () => false,
+    defineProperty: () => false,
+    deleteProperty: () => false,
+  },
+);
+
+// Imports that return a `result` throw the error payload, so turn it into a
+// regular Error.
+function callImport(fn) {
   try {
-    const result = await fn();
-    console.log(`73914D86-55DF-495D-BAD5-B45D571D154D`);
-    console.log(JSON.stringify(result) ?? "null");
-    console.log(`8C47F950-3E81-46B1-976E-177A89380038`);
+    return fn();
   } catch (error) {
-    console.error(`E8FEE14A-BBF5-4B08-9E00-6D61189D897D`);
-    console.error(formatError(error));
+    throw new Error(typeof error?.payload === "string" ? error.payload : formatError(error));
   }
 }
-export async function evaluateModule(code, method, args, options) {
+
+function formatLogArg(arg) {
+  if (typeof arg === "string") {
+    return arg;
+  }
+  if (arg instanceof Error) {
+    return `${arg.name}: ${arg.message}`;
+  }
+  if (typeof arg === "object" && arg !== null) {
+    try {
+      return JSON.stringify(arg);
+    } catch {
+      // e.g. circular references or BigInt values
+    }
+  }
+  return String(arg);
+}
+
+// Throwing one of these from an export that returns a `result` sets the
+// `err` case to `payload`.
+class ComponentError extends Error {
+  constructor(payload) {
+    super(typeof payload === "string" ? payload : "ComponentError");
+    this.payload = payload;
+  }
+}
+
+// Returns the encoded result of `fn`, or throws a `js-error`.
+async function output(fn, options) {
+  try {
+    let value = await fn();
+    if (options.stream && typeof value?.[Symbol.asyncIterator] === "function") {
+      value = await streamItems(value, options);
+    }
+    return encodeValue(value, options);
+  } catch (error) {
+    throw toJsError(error);
+  }
+}
+
+// Sends each value the iterator yields to the host, which blocks while the
+// embedder isn't ready for more. Returns the iterator's return value.
+async function streamItems(iterable, options) {
+  const iterator = iterable[Symbol.asyncIterator]();
+  while (true) {
+    const { done, value } = await iterator.next();
+    if (done) {
+      return value;
+    }
+    try {
+      callImport(() => streamItem(encodeValue(value, options)));
+    } catch (error) {
+      // Let the generator run its `finally` blocks.
+      await iterator.return?.();
+      throw error;
+    }
+  }
+}
+
+// Parameters and results are passed as JSON, except for byte arrays.
+function decodeValue(value, options) {
+  if (value.tag === "bytes") {
+    return value.val;
+  }
+  const parsed = JSON.parse(value.val);
+  return options.valueEncoding === "tagged" ? fromTagged(parsed) : parsed;
+}
+
+function encodeValue(value, options) {
+  if (value instanceof ArrayBuffer) {
+    return { tag: "bytes", val: new Uint8Array(value) };
+  }
+  if (value instanceof Uint8Array) {
+    return { tag: "bytes", val: value };
+  }
+  if (options.valueEncoding === "tagged") {
+    return { tag: "json", val: JSON.stringify(toTagged(value, new Set())) };
+  }
+  return { tag: "json", val: JSON.stringify(value) ?? "null" };
+}
+
+// The tagged encoding represents values that JSON can't as objects with a
+// `$type` key. Objects with their own `$type` key are wrapped, so they
+// aren't mistaken for tagged values.
+function toTagged(value, seen) {
+  switch (typeof value) {
+    case "undefined":
+    case "function":
+    case "symbol":
+      return { $type: "undefined" };
+    case "bigint":
+      return { $type: "bigint", value: value.toString() };
+    case "number":
+      if (Object.is(value, -0)) {
+        return { $type: "number", value: "-0" };
+      }
+      return Number.isFinite(value) ? value : { $type: "number", value: `${value}` };
+    case "string":
+    case "boolean":
+      return value;
+  }
+  if (value === null) {
+    return null;
+  }
+  if (seen.has(value)) {
+    throw new TypeError("Cannot encode a circular structure");
+  }
+  seen.add(value);
+  try {
+    if (value instanceof Date) {
+      return { $type: "date", value: Number.isNaN(value.getTime()) ? null : value.toISOString() };
+    }
+    if (value instanceof Map) {
+      return {
+        $type: "map",
+        entries: [...value].map(([key, item]) => [toTagged(key, seen), toTagged(item, seen)]),
+      };
+    }
+    if (value instanceof Set) {
+      return { $type: "set", values: [...value].map(item => toTagged(item, seen)) };
+    }
+    if (Array.isArray(value)) {
+      return value.map(item => toTagged(item, seen));
+    }
+    if (typeof value.toJSON === "function") {
+      return toTagged(value.toJSON(), seen);
+    }
+    const object = Object.fromEntries(
+      Object.entries(value).map(([key, item]) => [key, toTagged(item, seen)]),
+    );
+    return Object.hasOwn(value, "$type") ? { $type: "object", value: object } : object;
+  } finally {
+    seen.delete(value);
+  }
+}
+
+function fromTagged(value) {
+  if (Array.isArray(value)) {
+    return value.map(fromTagged);
+  }
+  if (typeof value !== "object" || value === null) {
+    return value;
+  }
+  switch (value.$type) {
+    case undefined:
+      return fromTaggedObject(value);
+    case "undefined":
+      return undefined;
+    case "bigint":
+      return BigInt(value.value);
+    case "number":
+      return Number(value.value);
+    case "date":
+      return new Date(value.value ?? NaN);
+    case "map":
+      return new Map(value.entries.map(([key, item]) => [fromTagged(key), fromTagged(item)]));
+    case "set":
+      return new Set(value.values.map(fromTagged));
+    case "object":
+      return fromTaggedObject(value.value);
+    default:
+      throw new TypeError(`Unknown $type in parameter: ${value.$type}`);
+  }
+}
+
+function fromTaggedObject(value) {
+  return Object.fromEntries(Object.entries(value).map(([key, item]) => [key, fromTagged(item)]));
+}
+
+function toJsError(error) {
+  const errors = [errorInfo(error)];
+  const seen = new Set([error]);
+  let current = error;
+  while (current instanceof Error && "cause" in current && !seen.has(current.cause)) {
+    current = current.cause;
+    seen.add(current);
+    errors.push(errorInfo(current));
+  }
+  return new ComponentError({ errors });
+}
+
+function errorInfo(error) {
+  if (error instanceof Error) {
+    return {
+      name: `${error.name}`,
+      message: `${error.message}`,
+      stack: typeof error.stack === "string" ? error.stack : undefined,
+      value: undefined,
+    };
+  }
+  let value;
+  try {
+    value = JSON.stringify(error);
+  } catch {
+    // e.g. circular references or BigInt values
+  }
+  return { name: undefined, message: formatError(error), stack: undefined, value };
+}
+
+// The phase that the host is charging fuel and time to, which is
+// `execute` unless the code is being compiled or an import is loading.
+// Only synchronous code is charged to another phase, as whatever runs
+// while it is waiting, e.g. a sibling import, belongs to a phase of its
+// own.
+let currentPhase = ["execute", undefined];
+
+function setPhase([phase, module]) {
+  currentPhase = [phase, module];
+  enterPhase(phase, module);
+}
+
+// Charges the fuel and time used by `fn` to `phase`, then goes back to the
+// phase it was called from. `fn` must not be async.
+function inPhase(phase, module, fn) {
+  const previous = currentPhase;
+  setPhase([phase, module]);
+  try {
+    return fn();
+  } finally {
+    setPhase(previous);
+  }
+}
+
+async function loadModuleSource(resolved) {
+  if (resolved.tag === "id") {
+    return inPhase("load-module", resolved.val, () => loadImport(resolved.val));
+  }
+  if (resolved.tag === "url") {
+    startFetch(resolved.val);
+    try {
+      const res = await fetch(resolved.val);
+      if (!res.ok) {
+        throw new Error(
+          `Failed to load module from URL: ${resolved.val}, status: ${res.status}: ${await res.text()}`,
+        );
+      }
+      return await res.text();
+    } finally {
+      finishFetch(resolved.val);
+    }
+  }
+  throw new Error("Unexpected tag");
+}
+
+async function loadModule(resolved) {
+  const source = await loadModuleSource(resolved);
+  return inPhase("load-module", resolved.val, () => compileModule(source, resolved.val));
+}
+
+// Returns a function that evaluates a compiled module, loading its
+// dependencies as needed. Modules in `preparedModules` are used instead
+// of loading and compiling the source again.
+function createModuleLoader(preparedModules) {
   // TODO: handle cycles
   const moduleCache = new Map();
   async function $import(modulePath, parent, parents) {
+    if (modulePath === HOST_MODULE) {
+      return host;
+    }
     const resolved = await resolveImportPath(modulePath, parent);
     const id = resolved.val;
     if (parents.includes(id)) {
@@ -31,37 +353,24 @@ export async function evaluateModule(code, method, args, options) {
       return moduleCache.get(id);
     }
     const modulePromise = Promise.resolve().then(async () => {
-      const moduleSource =
-        resolved.tag === "id"
-          ? await loadImport(id)
-          : resolved.tag === "url"
-            ? await fetch(id).then(async res => {
-                if (!res.ok) {
-                  throw new Error(
-                    `Failed to load module from URL: ${resolved.val}, status: ${res.status}: ${await res.text()}`,
-                  );
-                }
-                return await res.text();
-              })
-            : (() => {
-                throw new Error("Unexpected tag");
-              })();
-      return await evaluateCompiledModule(
-        compileModule(moduleSource, id),
-        id,
-        parents.concat([id]),
-      );
+      const compiled = preparedModules.get(id) ?? (await loadModule(resolved));
+      return await evaluateCompiledModule(compiled, id, parents.concat([id]), true);
     });
     moduleCache.set(id, modulePromise);
     return modulePromise;
   }
 
-  async function evaluateCompiledModule(compiled, moduleName, parents) {
+  async function evaluateCompiledModule(compiled, moduleName, parents, isImport) {
+    // An import's top level is charged to loading it, up to its first
+    // `await`.
+    const inModulePhase = run => (isImport ? inPhase("load-module", moduleName, run) : run());
     let fn;
     try {
-      fn = new Function(
-        `return (${compiled.code})\n//# sourceURL=${moduleName.replace(/\n/g, "")}`,
-      )();
+      fn = inModulePhase(() =>
+        new Function(
+          `return (${compiled.code})\n//# sourceURL=${moduleName.replace(/\n/g, "")}`,
+        )(),
+      );
     } catch {
       throw new Error(`Syntax error in module: ${moduleName}`);
     }
@@ -81,38 +390,135 @@ export async function evaluateModule(code, method, args, options) {
     if (compiled.hasDynamicImport) {
       dependencies.unshift(path => $import(path, moduleName, parents));
     }
-    return await fn(...dependencies);
+    return await inModulePhase(() => fn(...dependencies));
   }
 
-  await output(async () => {
-    const compiled = options.stripTypes
+  return evaluateCompiledModule;
+}
+
+function compileMainModule(code, options) {
+  return inPhase("compile", undefined, () =>
+    options.stripTypes
       ? stripTypesAndCompileModule(code, options.filename)
-      : compileModule(code, options.filename);
+      : compileModule(code, options.filename),
+  );
+}
+
+function compileFunction(code, options) {
+  if (!options.stripTypes) {
+    return `(${code})`;
+  }
+  return inPhase("compile", undefined, () => stripTypes(`(${code})`, options.filename));
+}
+
+function instantiateFunction(compiled, options) {
+  try {
+    return new Function(
+      `return ${compiled}\n//# sourceURL=${(options.filename ?? "input.js").replace(/\n/g, "")}`,
+    )();
+  } catch {
+    throw new Error(`Syntax error in function code`);
+  }
+}
+
+export async function evaluateModule(code, method, args, options) {
+  return await output(async () => {
+    const evaluateCompiledModule = createModuleLoader(new Map());
     const module = await evaluateCompiledModule(
-      compiled,
+      compileMainModule(code, options),
       options.filename ?? "<main>",
       [],
     );
     const fn = module[method];
-    return await fn(...args.map(arg => JSON.parse(arg)));
-  });
+    return await fn(...args.map(arg => decodeValue(arg, options)));
+  }, options);
 }
 
 export async function evaluate(code, args, options) {
-  await output(async () => {
-    const compiled = options.stripTypes
-      ? stripTypes(`(${code})`, options.filename)
-      : `(${code})`;
-    let fn;
-    try {
-      fn = new Function(
-        `return ${compiled}\n//# sourceURL=${(options.filename ?? "input.js").replace(/\n/g, "")}`,
-      )();
-    } catch {
-      throw new Error(`Syntax error in function code`);
+  return await output(async () => {
+    const fn = instantiateFunction(compileFunction(code, options), options);
+    return await fn(...args.map(arg => decodeValue(arg, options)));
+  }, options);
+}
+
+// Strips types and loads the static module graph, so that the result can
+// be passed to `evaluatePrepared` in a fresh sandbox without repeating
+// that work. Dynamic imports are still loaded when they are evaluated.
+export async function prepare(code, isModule, options) {
+  try {
+    if (!isModule) {
+      return { tag: "function-expression", val: compileFunction(code, options) };
+    }
+    const mainId = options.filename ?? "<main>";
+    const modules = [];
+    const seen = new Set([mainId]);
+    async function visit(id, compiled) {
+      modules.push({
+        id,
+        code: compiled.code,
+        staticImports: compiled.staticImports,
+        hasDynamicImport: compiled.hasDynamicImport,
+      });
+      for (const { source } of compiled.staticImports) {
+        if (source === HOST_MODULE) {
+          continue;
+        }
+        const resolved = await resolveImportPath(source, id);
+        if (seen.has(resolved.val)) {
+          continue;
+        }
+        seen.add(resolved.val);
+        await visit(resolved.val, await loadModule(resolved));
+      }
+    }
+    await visit(mainId, compileMainModule(code, options));
+    return { tag: "module-graph", val: modules };
+  } catch (error) {
+    throw toJsError(error);
+  }
+}
+
+export async function evaluatePrepared(script, method, args, options) {
+  return await output(async () => {
+    if (script.tag === "function-expression") {
+      const fn = instantiateFunction(script.val, options);
+      return await fn(...args.map(arg => decodeValue(arg, options)));
+    }
+    const [main, ...dependencies] = script.val;
+    const evaluateCompiledModule = createModuleLoader(
+      new Map(dependencies.map(module => [module.id, module])),
+    );
+    const module = await evaluateCompiledModule(main, main.id, []);
+    const fn = module[method];
+    return await fn(...args.map(arg => decodeValue(arg, options)));
+  }, options);
+}
+
+// The function or module loaded by `loadBatch`.
+let batchTarget;
+
+export async function loadBatch(code, isModule, options) {
+  try {
+    if (!isModule) {
+      batchTarget = instantiateFunction(compileFunction(code, options), options);
+      return;
     }
-    return await fn(...args.map(arg => JSON.parse(arg)));
-  });
+    const evaluateCompiledModule = createModuleLoader(new Map());
+    batchTarget = await evaluateCompiledModule(
+      compileMainModule(code, options),
+      options.filename ?? "<main>",
+      [],
+    );
+  } catch (error) {
+    throw toJsError(error);
+  }
+}
+
+export async function evaluateBatchItem(method, args, options) {
+  return await output(async () => {
+    const fn = method === undefined ? batchTarget : batchTarget[method];
+    return await fn(...args.map(arg => decodeValue(arg, options)));
+  }, options);
 }
 
 function formatError(error) {
@@ -5,6 +5,26 @@ import { join } from "node:path";
 import { createServer } from "node:http";
 import { spawn } from "node:child_process";
 
+interface StackFrame {
+  function: string | null;
+  filename: string;
+  line: number;
+  column: number;
+  async_boundary: boolean;
+}
+interface JavaScriptError {
+  name?: string;
+  message: string;
+  stack: StackFrame[];
+  value?: unknown;
+  cause?: JavaScriptError;
+}
+interface LogRecord {
+  level: "debug" | "info" | "warn" | "error";
+  message: string;
+  timestamp_ms: number;
+  location?: StackFrame;
+}
 interface EvaluateResult {
   fuel_consumed: number;
   fuel_remaining: number;
@@ -16,8 +36,22 @@ interface EvaluateResult {
     uri: string;
   }[];
   result: any;
+  error?: JavaScriptError;
+  host_calls: { name: string; success: boolean; duration_ms: number }[];
+  phases: {
+    phase: string;
+    module?: string;
+    fuel_consumed: number;
+    duration_ms: number;
+    fetch_ms?: number;
+  }[];
+  seed?: number;
+  virtual_time_elapsed_ms?: number;
+  profile?: { meta: object; threads: object[] };
   stderr: string;
   stdout: string;
+  logs: LogRecord[];
+  truncated: boolean;
   success: boolean;
 }
 type EvaluateResultMetricKeys =
@@ -25,6 +59,8 @@ type EvaluateResultMetricKeys =
   | "fuel_remaining"
   | "max_requested_memory_bytes"
   | "max_requested_table_elements";
+// Checked separately, because they include timings that vary between runs.
+type EvaluateResultTimedKeys = "logs" | "truncated" | "host_calls" | "phases";
 
 type StripTypesResult =
   | { success: true; code: string }
@@ -69,6 +105,19 @@ const server = createServer((req, res) => {
     );
     return;
   }
+  if (req.url === "/slow-sum.js") {
+    // Finishes loading after its siblings
+    setTimeout(() => {
+      res.writeHead(200, { "Content-Type": "text/javascript" });
+      res.end(`export function sum(a, b) { return a + b; }`);
+    }, 100);
+    return;
+  }
+  if (req.url === "/check-authorization") {
+    res.writeHead(200, { "Content-Type": "text/plain" });
+    res.end(req.headers.authorization === "Bearer ghp-test-token" ? "authorized" : "unauthorized");
+    return;
+  }
   if (req.url === "/to-redirect") {
     res.writeHead(302, { Location: "http://localhost:3002/from-redirect" });
     res.end("Redirecting");
@@ -138,14 +187,16 @@ async function run({
   filename,
   code,
   parameters,
+  profile,
 }: {
   filename?: string;
   code: string;
   parameters: any[];
+  profile?: boolean;
 }): Promise<EvaluateResult> {
   const response = await fetch("http://localhost:3000/evaluate", {
     method: "POST",
-    body: JSON.stringify({ filename, code, parameters }),
+    body: JSON.stringify({ filename, code, parameters, profile }),
     headers: { "Content-Type": "application/json" },
   });
   if (!response.ok) {
@@ -189,13 +240,17 @@ async function validateModule(input: {
 }
 async function expectRun(
   input: { filename?: string; code: string; parameters: any[] },
-  expected: Omit<EvaluateResult, EvaluateResultMetricKeys>,
+  expected: Omit<EvaluateResult, EvaluateResultMetricKeys | EvaluateResultTimedKeys>,
 ) {
   const {
     fuel_consumed,
     fuel_remaining,
     max_requested_memory_bytes,
     max_requested_table_elements,
+    logs,
+    truncated,
+    host_calls,
+    phases,
     ...result
   } = await run(input);
   result.outbound_requests.forEach(req => {
@@ -341,6 +396,11 @@ await expectRun(
     result: {
       error: "JavaScript error: out of memory",
     },
+    error: {
+      message: "out of memory",
+      stack: [],
+      value: "out of memory",
+    },
     stderr: "",
     stdout: "",
     success: false,
@@ -348,7 +408,7 @@ await expectRun(
 );
 
 {
-  const { success, stdout, stderr, result } = await run({
+  const { success, stdout, stderr, logs, truncated, result } = await run({
     filename: "error_test.ts",
     code: `
       export async function run() {
@@ -362,6 +422,34 @@ await expectRun(
   eq(success, false);
   eq(stdout, "Attempting to generate output\n");
   eq(stderr, "This is going to throw\n");
+  eq(
+    logs.map(({ timestamp_ms, ...log }) => log),
+    [
+      {
+        level: "info",
+        message: "Attempting to generate output",
+        location: {
+          function: "run",
+          filename: "error_test.ts",
+          line: 3,
+          column: 17,
+          async_boundary: false,
+        },
+      },
+      {
+        level: "error",
+        message: "This is going to throw",
+        location: {
+          function: "run",
+          filename: "error_test.ts",
+          line: 4,
+          column: 17,
+          async_boundary: false,
+        },
+      },
+    ],
+  );
+  eq(truncated, false);
   eq(result.error.startsWith(`JavaScript error: ${"Hello World".repeat(42)}`), true);
 }
 
@@ -396,6 +484,40 @@ await expectRun(
         `  async*run@error_test.ts:5:15`,
       ].join("\n"),
     },
+    error: {
+      name: "Error",
+      message: "Hello World",
+      stack: [
+        {
+          function: "c",
+          filename: "error_test.ts",
+          line: 15,
+          column: 15,
+          async_boundary: false,
+        },
+        {
+          function: "b",
+          filename: "error_test.ts",
+          line: 12,
+          column: 15,
+          async_boundary: false,
+        },
+        {
+          function: "a",
+          filename: "error_test.ts",
+          line: 9,
+          column: 15,
+          async_boundary: false,
+        },
+        {
+          function: "run",
+          filename: "error_test.ts",
+          line: 5,
+          column: 15,
+          async_boundary: true,
+        },
+      ],
+    },
     stderr: "",
     stdout: "",
     success: false,
@@ -523,6 +645,66 @@ await expectRun(
   },
 );
 
+{
+  // Fuel is charged to the phase that used it, including each import
+  const { result, phases, fuel_consumed } = await run({
+    code: `
+      import { fib } from 'http://localhost:3001/fib.js';
+      export async function run(n: number) {
+        return fib(n);
+      }
+    `,
+    parameters: [20],
+  });
+  eq(result, 6765);
+  eq(
+    phases.map(({ phase, module }) => [phase, module]),
+    [
+      ["INSTANTIATE", undefined],
+      ["EXECUTE", undefined],
+      ["COMPILE", undefined],
+      ["LOAD_MODULE", "http://localhost:3001/fib.js"],
+    ],
+  );
+  eq(
+    phases.reduce((total, phase) => total + phase.fuel_consumed, 0),
+    fuel_consumed,
+  );
+}
+
+{
+  // Sibling imports load concurrently, without the code that runs
+  // afterwards being charged to one of them
+  const { result, phases } = await run({
+    code: `
+      import { fib } from 'http://localhost:3001/fib.js';
+      import { sum } from 'http://localhost:3001/slow-sum.js';
+      export async function run(n: number) {
+        return sum(fib(n), 1);
+      }
+    `,
+    parameters: [25],
+  });
+  eq(result, 75026);
+  const fuel = (phase: string) =>
+    phases
+      .filter(usage => usage.phase === phase)
+      .reduce((total, usage) => total + usage.fuel_consumed, 0);
+  assert(
+    fuel("EXECUTE") > 10 * fuel("LOAD_MODULE"),
+    `EXECUTE used ${fuel("EXECUTE")} fuel, LOAD_MODULE used ${fuel("LOAD_MODULE")}`,
+  );
+  // Fetching each import is recorded against it, apart from its fuel
+  const fetchMs = (module: string) =>
+    phases.find(usage => usage.module === `http://localhost:3001/${module}`)?.fetch_ms;
+  assert(
+    fetchMs("slow-sum.js")! >= 100,
+    `slow-sum.js took ${fetchMs("slow-sum.js")}ms`,
+  );
+  assert(fetchMs("fib.js")! < fetchMs("slow-sum.js")!);
+  eq(phases.filter(usage => usage.fetch_ms !== undefined).length, 2);
+}
+
 await expectRun(
   {
     code: `
@@ -854,6 +1036,431 @@ await expectRun(
   },
 );
 
+await startServer({
+  SANDBOX_ENABLE_EVALUATE_STREAM: "true",
+  SANDBOX_ENABLE_EVALUATE_NDJSON: "true",
+  SANDBOX_ENABLE_EVALUATE_BATCH: "true",
+  SANDBOX_ENABLE_EVALUATE_MODULE_CALLS: "true",
+});
+{
+  const response = await fetch("http://localhost:3000/evaluate/stream", {
+    method: "POST",
+    body: JSON.stringify({
+      code: `function () { console.log("hello"); console.error("world"); return 42; }`,
+      parameters: [],
+    }),
+    headers: { "Content-Type": "application/json" },
+  });
+  eq(response.headers.get("content-type"), "text/event-stream");
+  const events = (await response.text())
+    .split("\n\n")
+    .filter(event => event)
+    .map(event => {
+      const lines = event.split("\n");
+      return {
+        event: lines.find(line => line.startsWith("event: "))?.slice("event: ".length),
+        data: lines
+          .filter(line => line.startsWith("data: ") || line === "data:")
+          .map(line => line.replace(/^data: ?/, ""))
+          .join("\n"),
+      };
+    });
+  eq(events.slice(0, 2), [
+    { event: "stdout", data: "hello\n" },
+    { event: "stderr", data: "world\n" },
+  ]);
+  eq(events.length, 3);
+  eq(events[2].event, "result");
+  eq(JSON.parse(events[2].data).result, 42);
+}
+
+{
+  const response = await fetch("http://localhost:3000/evaluate/ndjson", {
+    method: "POST",
+    body: JSON.stringify({
+      code: `async function* (count) {
+        for (let i = 0; i < count; i++) {
+          yield { i };
+        }
+        yield new Uint8Array([1, 2]);
+        return "done";
+      }`,
+      parameters: [2],
+    }),
+    headers: { "Content-Type": "application/json" },
+  });
+  eq(response.headers.get("content-type"), "application/x-ndjson");
+  const lines = (await response.text())
+    .split("\n")
+    .filter(line => line)
+    .map(line => JSON.parse(line));
+  eq(lines.slice(0, 3), [
+    { type: "item", value: { i: 0 } },
+    { type: "item", value: { i: 1 } },
+    { type: "item", value: { $bytes: "AQI=" } },
+  ]);
+  eq(lines.length, 4);
+  eq(lines[3].type, "result");
+  eq(lines[3].result, "done");
+}
+
+{
+  const evaluateBatch = async (body: object) => {
+    const response = await fetch("http://localhost:3000/evaluate_batch", {
+      method: "POST",
+      body: JSON.stringify(body),
+      headers: { "Content-Type": "application/json" },
+    });
+    return await response.json();
+  };
+  const code = `function (a, b) {
+    globalThis.count = (globalThis.count ?? 0) + 1;
+    while (a === "spin");
+    return [a + b, count];
+  }`;
+  const batch = [
+    [1, 2],
+    ["spin", 0],
+    [3, 4],
+  ];
+  const aggregate = await evaluateBatch({ code, batch });
+  eq(aggregate.load.success, true);
+  eq(
+    aggregate.items.map((item: EvaluateResult) => item.result),
+    [[3, 1], { error: "CPU fuel exhausted" }, { error: "Session terminated by an earlier error" }],
+  );
+  // Only the operator can choose the budget
+  const requested = await evaluateBatch({ code, batch, budget: "PER_ITEM" });
+  eq(
+    requested.items.map((item: EvaluateResult) => item.result),
+    aggregate.items.map((item: EvaluateResult) => item.result),
+  );
+  const syntaxError = await evaluateBatch({ code: "function (", batch });
+  eq(syntaxError.load.success, false);
+  eq(syntaxError.items, []);
+}
+
+{
+  const response = await fetch("http://localhost:3000/evaluate_module_calls", {
+    method: "POST",
+    body: JSON.stringify({
+      code: `const seen = [];
+        export function add(x) { seen.push(x); return seen.length; }
+        export function list() { return seen; }`,
+      calls: [
+        { method: "add", parameters: ["a"] },
+        { method: "add", parameters: ["b"] },
+        { method: "missing" },
+        { method: "list" },
+      ],
+    }),
+    headers: { "Content-Type": "application/json" },
+  });
+  const result = await response.json();
+  eq(result.load.success, true);
+  eq(result.items[0].result, 1);
+  eq(result.items[1].result, 2);
+  eq(result.items[2].success, false);
+  eq(result.items[3].result, ["a", "b"]);
+}
+
+await startServer({
+  SANDBOX_ENABLE_EVALUATE_BATCH: "true",
+  SANDBOX_BATCH_BUDGET: "PER_ITEM",
+  SANDBOX_BATCH_MAX_ITEMS: "3",
+});
+{
+  const evaluateBatch = async (batch: unknown[][]) =>
+    await fetch("http://localhost:3000/evaluate_batch", {
+      method: "POST",
+      body: JSON.stringify({
+        code: `function (a, b) {
+          globalThis.count = (globalThis.count ?? 0) + 1;
+          while (a === "spin");
+          return [a + b, count];
+        }`,
+        batch,
+      }),
+      headers: { "Content-Type": "application/json" },
+    });
+  const perItem = await (
+    await evaluateBatch([
+      [1, 2],
+      ["spin", 0],
+      [3, 4],
+    ])
+  ).json();
+  eq(
+    perItem.items.map((item: EvaluateResult) => item.result),
+    [[3, 1], { error: "CPU fuel exhausted" }, [7, 1]],
+  );
+  const tooLong = await evaluateBatch([[1, 2], [3, 4], [5, 6], [7, 8]]);
+  eq(tooLong.status, 400);
+  eq(await tooLong.text(), "A batch can have at most 3 items");
+}
+
+{
+  // Passing a seed makes random numbers reproducible, without fixing the clock
+  const evaluateWithSeed = async (seed: number): Promise<EvaluateResult> => {
+    const response = await fetch("http://localhost:3000/evaluate", {
+      method: "POST",
+      body: JSON.stringify({
+        code: `function () { return [Date.now(), Math.random()]; }`,
+        parameters: [],
+        seed,
+      }),
+      headers: { "Content-Type": "application/json" },
+    });
+    return await response.json();
+  };
+  const before = Date.now();
+  const first = await evaluateWithSeed(42);
+  const second = await evaluateWithSeed(42);
+  eq(first.seed, 42);
+  assert(first.result[0] >= before && first.result[0] <= Date.now());
+  eq(first.result[1], second.result[1]);
+  assert((await evaluateWithSeed(43)).result[1] !== first.result[1]);
+}
+
+{
+  // The server doesn't register any host functions
+  const { result, host_calls } = await run({
+    code: `async function () {
+      try {
+        await host.lookup("example");
+      } catch (error) {
+        return error.message;
+      }
+    }`,
+    parameters: [],
+  });
+  eq(result, "Unknown host function: lookup");
+  eq(
+    host_calls.map(({ name, success }) => ({ name, success })),
+    [{ name: "lookup", success: false }],
+  );
+}
+
+{
+  // Byte arrays are passed as base64
+  const { result } = await run({
+    code: `function (bytes) {
+      return bytes.map(b => b * 2);
+    }`,
+    parameters: [{ $bytes: Buffer.from([1, 2, 3]).toString("base64") }],
+  });
+  eq(result, { $bytes: Buffer.from([2, 4, 6]).toString("base64") });
+}
+
+{
+  // Objects that look like byte arrays are escaped
+  const { result } = await run({
+    code: `function (value) {
+      return [typeof value.$bytes, value];
+    }`,
+    parameters: [{ $bytes: { $escaped: "AQI=" } }],
+  });
+  eq(result, ["string", { $bytes: "AQI=" }]);
+  const { result: returned } = await run({
+    code: `function () {
+      return { $bytes: "AQI=" };
+    }`,
+    parameters: [],
+  });
+  eq(returned, { $bytes: { $escaped: "AQI=" } });
+}
+
+{
+  // The tagged encoding round trips values that JSON can't represent
+  const response = await fetch("http://localhost:3000/evaluate", {
+    method: "POST",
+    body: JSON.stringify({
+      code: `function (value) {
+        return { value, type: value.constructor.name, missing: undefined };
+      }`,
+      parameters: [{ $type: "set", values: [{ $type: "bigint", value: "1" }] }],
+      value_encoding: "TAGGED",
+    }),
+    headers: { "Content-Type": "application/json" },
+  });
+  const { result } = (await response.json()) as EvaluateResult;
+  eq(result, {
+    value: { $type: "set", values: [{ $type: "bigint", value: "1" }] },
+    type: "Set",
+    missing: { $type: "undefined" },
+  });
+}
+
+await startServer({
+  SANDBOX_KV_STORE: "MEMORY",
+  SANDBOX_KV_NAMESPACE: "a",
+  SANDBOX_KV_MAX_OPERATIONS: "3",
+  SANDBOX_KV_MAX_KEYS: "1",
+});
+{
+  async function evaluateWithKv(kv_namespace: string, code: string) {
+    const response = await fetch("http://localhost:3000/evaluate", {
+      method: "POST",
+      body: JSON.stringify({ code, parameters: [], kv_namespace }),
+      headers: { "Content-Type": "application/json" },
+    });
+    return (await response.json()) as EvaluateResult;
+  }
+  eq((await evaluateWithKv("a", `async function () { await kv.set("count", 1); }`)).success, true);
+  eq(
+    (await evaluateWithKv("a", `async function () { return await kv.get("count"); }`)).result,
+    1,
+  );
+  // Requests can't choose another namespace
+  eq(
+    (await evaluateWithKv("b", `async function () { return await kv.get("count") ?? null; }`))
+      .result,
+    1,
+  );
+  const limited = await evaluateWithKv(
+    "a",
+    `async function () { for (let i = 0; i < 4; i++) await kv.get("count"); }`,
+  );
+  eq(limited.error?.message, "KV operation limit exceeded");
+  const tooManyKeys = await evaluateWithKv("a", `async function () { await kv.set("other", 1); }`);
+  eq(tooManyKeys.error?.message, "KV key limit exceeded");
+}
+
+await startServer({ SANDBOX_RESULT_MAX_BYTES: "1KB", SANDBOX_RESULT_MAX_DEPTH: "10" });
+{
+  const tooLarge = await run({
+    code: `function () { return "x".repeat(2000); }`,
+    parameters: [],
+  });
+  eq(tooLarge.success, false);
+  eq(tooLarge.result, { error: "Result is larger than the limit of 1024 bytes" });
+  const tooDeep = await run({
+    code: `function () {
+      let value = [];
+      for (let i = 0; i < 20; i++) value = [value];
+      return value;
+    }`,
+    parameters: [],
+  });
+  eq(tooDeep.result, { error: "Result is nested more than 10 levels deep" });
+}
+
+await startServer({ SANDBOX_VIRTUAL_TIME: "true" });
+{
+  const start = Date.now();
+  const { result, virtual_time_elapsed_ms } = await run({
+    code: `async function () {
+      await new Promise(resolve => setTimeout(resolve, 60_000));
+      return "done";
+    }`,
+    parameters: [],
+  });
+  eq(result, "done");
+  eq(virtual_time_elapsed_ms, 60_000);
+  assert(Date.now() - start < 10_000);
+}
+{
+  // Profiles are only returned if the server allows it
+  const { profile } = await run({ code: `function () {}`, parameters: [], profile: true });
+  eq(profile, undefined);
+}
+
+await startServer({ SANDBOX_CPU_TIME: "50ms" });
+{
+  const { result, fuel_consumed } = await run({
+    code: `function () { while (true); }`,
+    parameters: [],
+  });
+  eq(result, { error: "CPU fuel exhausted" });
+  assert(fuel_consumed > 1_000_000);
+}
+
+await startServer({ SANDBOX_ALLOW_PROFILING: "true" });
+{
+  const { result, profile } = await run({
+    code: `function () {
+      const end = Date.now() + 100;
+      while (Date.now() < end);
+      return "done";
+    }`,
+    parameters: [],
+    profile: true,
+  });
+  eq(result, "done");
+  assert(typeof profile?.meta === "object");
+  assert(profile.threads.length > 0);
+}
+
+await startServer({
+  SANDBOX_HTTP_MODE: "ALLOW_ALL",
+  SANDBOX_SECRETS_FILE: join(import.meta.dirname, "secrets", "secrets.json"),
+});
+{
+  const { stdout, logs, error } = await run({
+    code: `function () {
+      console.log(secrets.api_key);
+      const length = secrets.api_key.length;
+      throw new Error("Invalid key: " + secrets.api_key + " (" + length + " characters)");
+    }`,
+    parameters: [],
+  });
+  eq(stdout, "[REDACTED]\n");
+  eq(logs[0].message, "[REDACTED]");
+  eq(error?.message, "Invalid key: [REDACTED] (18 characters)");
+}
+{
+  // Bound secrets can't be read, but are substituted into request headers
+  // for the hosts they are bound to.
+  const { result } = await run({
+    code: `async function () {
+      const headers = { Authorization: "Bearer {{secret:github}}" };
+      return [
+        secrets.github ?? null,
+        await (await fetch("http://localhost:3001/check-authorization", { headers })).text(),
+        await (await fetch("http://127.0.0.1:3001/check-authorization", { headers })).text(),
+      ];
+    }`,
+    parameters: [],
+  });
+  eq(result, [null, "authorized", "unauthorized"]);
+}
+
+await startServer({ SANDBOX_ENABLE_SESSIONS: "true" });
+{
+  async function post(path: string, body: unknown) {
+    const response = await fetch(`http://localhost:3000${path}`, {
+      method: "POST",
+      body: JSON.stringify(body),
+      headers: { "Content-Type": "application/json" },
+    });
+    return { status: response.status, body: await response.text() };
+  }
+  const created = await post("/sessions", {
+    code: `function (start) { globalThis.count = start; }`,
+    parameters: [40],
+  });
+  eq(created.status, 200);
+  const { session_id: sessionId } = JSON.parse(created.body);
+  assert(typeof sessionId === "string");
+  for (const expected of [41, 42]) {
+    const called = await post(`/sessions/${sessionId}/evaluate`, {
+      code: `function () { return ++count; }`,
+      parameters: [],
+    });
+    eq(called.status, 200);
+    eq(JSON.parse(called.body).result, expected);
+  }
+  const deleted = await fetch(`http://localhost:3000/sessions/${sessionId}`, {
+    method: "DELETE",
+  });
+  eq(deleted.status, 204);
+  const afterDelete = await post(`/sessions/${sessionId}/evaluate`, {
+    code: `function () { return count; }`,
+    parameters: [],
+  });
+  eq(afterDelete.status, 404);
+}
+
 if (proc) proc.kill();
 server.close();
 redirectDestinationServer.close();